@@ -0,0 +1,55 @@
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tokio_memcache::{ClientCodec, Request, Response, ServerCodec, Value};
+use tokio_util::codec::Decoder;
+
+fn values(count: usize, len: usize) -> BytesMut {
+    let mut buf = Vec::new();
+    let values = (0..count).map(|index| Value{key: format!("key{}", index), value: vec![b'x'; len].into(), flags: 0, cas: Some(index as u64)}).collect();
+    Response::Values(values).build(&mut buf);
+    BytesMut::from(&buf[..])
+}
+
+fn set(len: usize) -> BytesMut {
+    let mut buf = Vec::new();
+    Request::Set{key: String::from("key"), value: vec![b'x'; len].into(), flags: 0, expiry: 0, noreply: false}.build(&mut buf).unwrap();
+    BytesMut::from(&buf[..])
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for &(count, len) in &[(1, 1024 * 1024), (100, 100), (1000, 10)] {
+        let buf = values(count, len);
+        group.throughput(Throughput::Bytes(buf.len() as u64));
+        group.bench_function(format!("values/{}x{}", count, len), |b| {
+            b.iter_batched(|| buf.clone(), |mut buf| ClientCodec::new().decode(&mut buf).unwrap().unwrap(), BatchSize::SmallInput)
+        });
+    }
+    for &len in &[100, 1024 * 1024] {
+        let buf = set(len);
+        group.throughput(Throughput::Bytes(buf.len() as u64));
+        group.bench_function(format!("set/{}", len), |b| {
+            b.iter_batched(|| buf.clone(), |mut buf| ServerCodec::new().decode(&mut buf).unwrap().unwrap(), BatchSize::SmallInput)
+        });
+    }
+    // A large response arriving over many reads, as it would from a socket.
+    let buf = values(1000, 1024);
+    group.throughput(Throughput::Bytes(buf.len() as u64));
+    group.bench_function("values/1000x1024/reads", |b| {
+        b.iter(|| {
+            let mut codec = ClientCodec::new();
+            let mut read = BytesMut::new();
+            for chunk in buf.chunks(16 * 1024) {
+                read.extend_from_slice(chunk);
+                if let Some(rsp) = codec.decode(&mut read).unwrap() {
+                    return rsp;
+                }
+            }
+            panic!("incomplete response");
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
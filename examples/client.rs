@@ -1,31 +1,14 @@
-#[macro_use]
-extern crate futures;
-extern crate tokio_core;
-extern crate tokio_memcache;
-
-use futures::Future;
-use tokio_core::reactor::Core;
+use bytes::Bytes;
 use tokio_memcache::{Client, Api, ApiHelper};
 
-pub fn main() {
-    let mut core = Core::new().unwrap();
+#[tokio::main]
+pub async fn main() {
     let addr = "127.0.0.1:11211".parse().unwrap();
-    let handle = core.handle();
 
-    core.run(
-        Client::connect(&addr, &handle)
-        .and_then(|client| {
-            client.version()
-            .and_then(move |version| {
-                println!("Version: {}", version);
-                client.set(String::from("abcd"), b"blah".to_vec(), 0, 0)
-                .and_then(move |_| {
-                    client.get_one(String::from("abcd"))
-                    .and_then(|value| {
-                        println!("{:?}", value);
-                        Ok(())
-                        })
-                    })
-            })
-        })).unwrap();
+    let client = Client::connect(&addr).await.unwrap();
+    let version = client.version().await.unwrap();
+    println!("Version: {}", version);
+    client.set(String::from("abcd"), Bytes::from_static(b"blah"), 0, 0).await.unwrap();
+    let value = client.get_one(String::from("abcd")).await.unwrap();
+    println!("{:?}", value);
 }
@@ -1,68 +1,69 @@
-#[macro_use]
-extern crate futures;
-extern crate tokio_memcache;
-
-use futures::future;
-use futures::future::FutureResult;
-use tokio_memcache::{Api, ApiService, Value};
+use bytes::Bytes;
+use futures::future::{self, Ready};
+use std::time::Duration;
+use tokio_memcache::{Api, ApiService, LoggingLayer, ServiceBuilder, TimeoutLayer, Value};
 
 pub struct ApiImpl;
 
 #[allow(unused_variables)]
 impl Api<::std::io::Error> for ApiImpl {
-    type FutureUnit = FutureResult<(), ::std::io::Error>;
-    type FutureValues = FutureResult<Vec<Value>, ::std::io::Error>;
-    type FutureU64 = FutureResult<u64, ::std::io::Error>;
-    type FutureString = FutureResult<String, ::std::io::Error>;
+    type FutureUnit = Ready<Result<(), ::std::io::Error>>;
+    type FutureValues = Ready<Result<Vec<Value>, ::std::io::Error>>;
+    type FutureU64 = Ready<Result<u64, ::std::io::Error>>;
+    type FutureString = Ready<Result<String, ::std::io::Error>>;
 
-    fn set(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32) -> Self::FutureUnit {
-        future::done(Ok(()))
+    fn set(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit {
+        future::ready(Ok(()))
     }
-    fn add(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32) -> Self::FutureUnit {
-        future::done(Ok(()))
+    fn add(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit {
+        future::ready(Ok(()))
     }
-    fn replace(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32) -> Self::FutureUnit {
-        future::done(Ok(()))
+    fn replace(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit {
+        future::ready(Ok(()))
     }
-    fn append(&self, key: String, value: Vec<u8>) -> Self::FutureUnit {
-        future::done(Ok(()))
+    fn append(&self, key: String, value: Bytes) -> Self::FutureUnit {
+        future::ready(Ok(()))
     }
-    fn prepend(&self, key: String, value: Vec<u8>) -> Self::FutureUnit {
-        future::done(Ok(()))
+    fn prepend(&self, key: String, value: Bytes) -> Self::FutureUnit {
+        future::ready(Ok(()))
     }
-    fn cas(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32, cas: u64) -> Self::FutureUnit {
-        future::done(Ok(()))
+    fn cas(&self, key: String, value: Bytes, flags: u16, expiry: u32, cas: u64) -> Self::FutureUnit {
+        future::ready(Ok(()))
     }
     fn get(&self, keys: Vec<String>) -> Self::FutureValues {
-        future::done(Ok(keys.iter().map(|key| Value{key: key.clone(), value: (key.clone() + "'s value").as_bytes().to_vec(), flags: 0, cas: None}).collect()))
+        future::ready(Ok(keys.iter().map(|key| Value{key: key.clone(), value: Bytes::from(key.clone() + "'s value"), flags: 0, cas: None}).collect()))
     }
     fn gets(&self, keys: Vec<String>) -> Self::FutureValues {
-        future::done(Ok(keys.iter().map(|key| Value{key: key.clone(), value: (key.clone() + "'s value").as_bytes().to_vec(), flags: 0, cas: Some(8)}).collect()))
+        future::ready(Ok(keys.iter().map(|key| Value{key: key.clone(), value: Bytes::from(key.clone() + "'s value"), flags: 0, cas: Some(8)}).collect()))
     }
     fn delete(&self, key: String) -> Self::FutureUnit {
-        future::done(Ok(()))
+        future::ready(Ok(()))
     }
     fn incr(&self, key: String, value: u64) -> Self::FutureU64 {
-        future::done(Ok(4))
+        future::ready(Ok(4))
     }
     fn decr(&self, key: String, value: u64) -> Self::FutureU64 {
-        future::done(Ok(2))
+        future::ready(Ok(2))
     }
     fn touch(&self, key: String, expiry: u32) -> Self::FutureUnit {
-        future::done(Ok(()))
+        future::ready(Ok(()))
     }
     fn flush_all(&self, delay: u32) -> Self::FutureUnit {
-        future::done(Ok(()))
+        future::ready(Ok(()))
     }
     fn version(&self) -> Self::FutureString {
-        future::done(Ok(String::from("1.2.3.4")))
+        future::ready(Ok(String::from("1.2.3.4")))
     }
 }
 
-pub fn main() {
+#[tokio::main]
+pub async fn main() {
     let addr = "127.0.0.1:11211".parse().unwrap();
 
-    tokio_memcache::serve(addr, || {
-        Ok(ApiService::new(ApiImpl{}))
-    });
+    let layers = ServiceBuilder::new()
+        .layer(LoggingLayer)
+        .layer(TimeoutLayer::new(Duration::from_secs(1)));
+    tokio_memcache::serve(addr, move || {
+        Ok(layers.service(ApiService::new(ApiImpl{})))
+    }).await.unwrap();
 }
@@ -1,18 +1,23 @@
-use futures::{Future, Map};
-use value::Value;
+use bytes::Bytes;
+use futures::FutureExt;
+use futures::future::Map;
+use std::future::Future;
+use std::io;
+
+use crate::value::Value;
 
 pub trait Api<E> {
-    type FutureUnit: Future<Item = (), Error = E> + Sized;
-    type FutureValues: Future<Item = Vec<Value>, Error = E> + Sized;
-    type FutureU64: Future<Item = u64, Error = E> + Sized;
-    type FutureString: Future<Item = String, Error = E> + Sized;
-
-    fn set(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32) -> Self::FutureUnit;
-    fn add(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32) -> Self::FutureUnit;
-    fn replace(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32) -> Self::FutureUnit;
-    fn append(&self, key: String, value: Vec<u8>) -> Self::FutureUnit;
-    fn prepend(&self, key: String, value: Vec<u8>) -> Self::FutureUnit;
-    fn cas(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32, cas: u64) -> Self::FutureUnit;
+    type FutureUnit: Future<Output = Result<(), E>> + Send + 'static;
+    type FutureValues: Future<Output = Result<Vec<Value>, E>> + Send + 'static;
+    type FutureU64: Future<Output = Result<u64, E>> + Send + 'static;
+    type FutureString: Future<Output = Result<String, E>> + Send + 'static;
+
+    fn set(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit;
+    fn add(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit;
+    fn replace(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit;
+    fn append(&self, key: String, value: Bytes) -> Self::FutureUnit;
+    fn prepend(&self, key: String, value: Bytes) -> Self::FutureUnit;
+    fn cas(&self, key: String, value: Bytes, flags: u16, expiry: u32, cas: u64) -> Self::FutureUnit;
     fn get(&self, keys: Vec<String>) -> Self::FutureValues;
     fn gets(&self, keys: Vec<String>) -> Self::FutureValues;
     fn delete(&self, key: String) -> Self::FutureUnit;
@@ -24,27 +29,46 @@ pub trait Api<E> {
 }
 
 pub trait ApiHelper<E> {
-    type FutureValue: Future<Item = Value, Error = E> + Sized;
+    type FutureValue: Future<Output = Result<Value, E>> + Send + 'static;
 
     fn get_one(&self, key: String) -> Self::FutureValue;
     fn gets_one(&self, key: String) -> Self::FutureValue;
 }
 
+// A miss is reported as a `NotFound` error.
 impl<T, E> ApiHelper<E> for T
-    where T: Api<E> {
-    type FutureValue = Map<T::FutureValues, fn(Vec<Value>) -> Value>;
+    where T: Api<E>,
+          E: From<io::Error> + 'static {
+    type FutureValue = Map<T::FutureValues, fn(Result<Vec<Value>, E>) -> Result<Value, E>>;
 
     fn get_one(&self, key: String) -> Self::FutureValue {
-        fn map(values: Vec<Value>) -> Value {
-            values[0].clone()
-        }
-        self.get(vec![key]).map(map)
+        self.get(vec![key]).map(first::<E>)
     }
 
     fn gets_one(&self, key: String) -> Self::FutureValue {
-        fn map(values: Vec<Value>) -> Value {
-            values[0].clone()
-        }
-        self.gets(vec![key]).map(map)
+        self.gets(vec![key]).map(first::<E>)
+    }
+}
+
+fn first<E: From<io::Error>>(values: Result<Vec<Value>, E>) -> Result<Value, E> {
+    values?.into_iter().next().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use std::io;
+    use crate::api::{Api, ApiHelper};
+    use crate::memory::InMemory;
+
+    #[test]
+    fn one() {
+        let cache = InMemory::new();
+        block_on(cache.set(String::from("a"), Bytes::from_static(b"1"), 0, 0)).unwrap();
+        assert_eq!(&block_on(cache.get_one(String::from("a"))).unwrap().value[..], b"1");
+        assert!(block_on(cache.gets_one(String::from("a"))).unwrap().cas.is_some());
+        assert_eq!(block_on(cache.get_one(String::from("b"))).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(block_on(cache.gets_one(String::from("b"))).unwrap_err().kind(), io::ErrorKind::NotFound);
     }
 }
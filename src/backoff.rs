@@ -0,0 +1,72 @@
+use std::cmp;
+use std::time::Duration;
+use rand::{self, Rng};
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    multiplier: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff{initial: initial, max: max, multiplier: 2}
+    }
+
+    pub fn with_multiplier(self, multiplier: u32) -> Backoff {
+        Backoff{multiplier: cmp::max(multiplier, 1), ..self}
+    }
+
+    // Exponential backoff with "equal jitter": the delay for a given attempt
+    // is uniformly distributed between half and all of the capped exponential
+    // delay, so retries spread out without ever collapsing to zero.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt);
+        let ceiling_ms = ceiling.as_secs() * 1000 + ceiling.subsec_millis() as u64;
+        let half_ms = ceiling_ms / 2;
+        if half_ms == 0 {
+            return ceiling;
+        }
+        Duration::from_millis(half_ms + rand::thread_rng().gen_range(0, ceiling_ms - half_ms + 1))
+    }
+
+    fn ceiling(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial;
+        for _ in 0..attempt {
+            delay = match delay.checked_mul(self.multiplier) {
+                Some(delay) if delay < self.max => delay,
+                _ => return self.max,
+            };
+        }
+        cmp::min(delay, self.max)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new(Duration::from_millis(100), Duration::from_secs(30))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::backoff::Backoff;
+
+    #[test]
+    fn delay() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        for (attempt, ceiling) in [(0, 100), (1, 200), (2, 400), (3, 800), (4, 1000), (40, 1000)] {
+            for _ in 0..20 {
+                let delay = backoff.delay(attempt);
+                assert!(delay >= Duration::from_millis(ceiling / 2) && delay <= Duration::from_millis(ceiling), "{:?} for attempt {}", delay, attempt);
+            }
+        }
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_secs(60)).with_multiplier(10);
+        assert!(backoff.delay(2) >= Duration::from_millis(500));
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_secs(60)).with_multiplier(0);
+        assert!(backoff.delay(30) <= Duration::from_millis(10));
+        assert_eq!(Backoff::new(Duration::from_millis(1), Duration::from_secs(1)).delay(0), Duration::from_millis(1));
+    }
+}
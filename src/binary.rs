@@ -0,0 +1,325 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use std::cmp;
+use std::io;
+use std::str;
+
+use crate::proto::{MAX_VALUE_LEN, READ_AHEAD};
+use crate::request::Request;
+use crate::response::Response;
+use crate::value::Value;
+
+const REQUEST: u8 = 0x80;
+const RESPONSE: u8 = 0x81;
+const HEADER_LEN: usize = 24;
+
+const SET: u8 = 0x01;
+const ADD: u8 = 0x02;
+const REPLACE: u8 = 0x03;
+const DELETE: u8 = 0x04;
+const INCREMENT: u8 = 0x05;
+const DECREMENT: u8 = 0x06;
+const FLUSH: u8 = 0x08;
+const NOOP: u8 = 0x0a;
+const VERSION: u8 = 0x0b;
+const GETKQ: u8 = 0x0d;
+const APPEND: u8 = 0x0e;
+const PREPEND: u8 = 0x0f;
+const TOUCH: u8 = 0x1c;
+
+const NO_ERROR: u16 = 0x00;
+const KEY_NOT_FOUND: u16 = 0x01;
+const KEY_EXISTS: u16 = 0x02;
+const VALUE_TOO_LARGE: u16 = 0x03;
+const INVALID_ARGUMENTS: u16 = 0x04;
+const NOT_STORED: u16 = 0x05;
+const NON_NUMERIC: u16 = 0x06;
+const UNKNOWN_COMMAND: u16 = 0x81;
+
+// An incr or decr expiry that fails on a missing key rather than creating
+// it, as the text protocol does.
+const NO_CREATE: u32 = 0xffff_ffff;
+
+// A response packet of the memcached binary protocol.  `opaque` is whatever
+// the request carried, which lets responses be matched to requests in any
+// order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub opcode: u8,
+    pub status: u16,
+    pub opaque: u32,
+    pub cas: u64,
+    pub extras: Bytes,
+    pub key: Bytes,
+    pub value: Bytes,
+}
+
+impl Packet {
+    // Whether this is one of the values of a multi-get, which is answered by
+    // the no-op after them.
+    pub fn is_value(&self) -> bool {
+        self.opcode == GETKQ && self.status == NO_ERROR
+    }
+
+    // Whether this is a key of a multi-get that failed, e.g. with a value too
+    // large.  The multi-get still ends with its no-op, and `to_response`
+    // gives the error.
+    pub fn is_value_error(&self) -> bool {
+        self.opcode == GETKQ && self.status != NO_ERROR
+    }
+
+    // The value carried by a multi-get hit.
+    pub fn to_value(&self) -> io::Result<Value> {
+        if self.extras.len() != 4 {
+            return Err(invalid_data("value without flags"));
+        }
+        let flags = (&self.extras[..]).get_u32();
+        if flags > u16::MAX as u32 {
+            return Err(invalid_data("flags out of range"));
+        }
+        let key = str::from_utf8(&self.key).map_err(|_| invalid_data("key is not UTF-8"))?;
+        Ok(Value{key: key.to_string(), value: self.value.clone(), flags: flags as u16, cas: Some(self.cas)})
+    }
+
+    // The response the text protocol would give, apart from the values of a
+    // multi-get, which the caller gathers up to the closing no-op.
+    pub fn to_response(&self) -> io::Result<Response> {
+        let message = || String::from_utf8_lossy(&self.value).into_owned();
+        let rsp = match (self.opcode, self.status) {
+            (SET, NO_ERROR) | (ADD, NO_ERROR) | (REPLACE, NO_ERROR) | (APPEND, NO_ERROR) | (PREPEND, NO_ERROR) => Response::Stored,
+            (ADD, KEY_EXISTS) | (REPLACE, KEY_NOT_FOUND) | (APPEND, KEY_NOT_FOUND) | (PREPEND, KEY_NOT_FOUND) | (_, NOT_STORED) => Response::NotStored,
+            (DELETE, NO_ERROR) => Response::Deleted,
+            (INCREMENT, NO_ERROR) | (DECREMENT, NO_ERROR) => {
+                if self.value.len() != 8 {
+                    return Err(invalid_data("counter value is not 8 bytes"));
+                }
+                Response::UpdatedValue((&self.value[..]).get_u64())
+            },
+            (TOUCH, NO_ERROR) => Response::Touched,
+            (FLUSH, NO_ERROR) => Response::Ok,
+            (VERSION, NO_ERROR) => Response::Version(message()),
+            (NOOP, NO_ERROR) => Response::Values(Vec::new()),
+            (_, KEY_EXISTS) => Response::Exists,
+            (_, KEY_NOT_FOUND) => Response::NotFound,
+            (_, INVALID_ARGUMENTS) | (_, NON_NUMERIC) => Response::ClientError(message()),
+            (_, VALUE_TOO_LARGE) => Response::ServerError(message()),
+            (_, UNKNOWN_COMMAND) => Response::Error,
+            (opcode, NO_ERROR) => return Err(invalid_data(&format!("unexpected response to opcode {:#04x}", opcode))),
+            (_, _) => Response::ServerError(message()),
+        };
+        Ok(rsp)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Writes a packet with the given header fields and body.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn put_packet<B: BufMut>(buf: &mut B, magic: u8, opcode: u8, status: u16, opaque: u32, cas: u64, extras: &[u8], key: &[u8], value: &[u8]) {
+    buf.put_u8(magic);
+    buf.put_u8(opcode);
+    buf.put_u16(key.len() as u16);
+    buf.put_u8(extras.len() as u8);
+    buf.put_u8(0);
+    buf.put_u16(status);
+    buf.put_u32((extras.len() + key.len() + value.len()) as u32);
+    buf.put_u32(opaque);
+    buf.put_u64(cas);
+    buf.put_slice(extras);
+    buf.put_slice(key);
+    buf.put_slice(value);
+}
+
+fn storage_extras(flags: u16, expiry: u32) -> [u8; 8] {
+    let mut extras = [0; 8];
+    extras[..4].copy_from_slice(&(flags as u32).to_be_bytes());
+    extras[4..].copy_from_slice(&expiry.to_be_bytes());
+    extras
+}
+
+fn counter_extras(delta: u64) -> [u8; 20] {
+    let mut extras = [0; 20];
+    extras[..8].copy_from_slice(&delta.to_be_bytes());
+    extras[16..].copy_from_slice(&NO_CREATE.to_be_bytes());
+    extras
+}
+
+// Writes `req` as binary protocol packets carrying `opaque`.  A get of any
+// number of keys is written as a quiet get for each, which is only answered
+// on a hit, followed by a no-op whose answer marks the end of the values.
+// Every other request is a single packet with exactly one response, so
+// `noreply` is ignored.
+pub fn build<B: BufMut>(req: &Request, opaque: u32, buf: &mut B) -> io::Result<()> {
+    req.validate()?;
+    match *req {
+        Request::Set{ref key, ref value, flags, expiry, noreply: _} => put_packet(buf, REQUEST, SET, 0, opaque, 0, &storage_extras(flags, expiry), key.as_bytes(), value),
+        Request::Add{ref key, ref value, flags, expiry, noreply: _} => put_packet(buf, REQUEST, ADD, 0, opaque, 0, &storage_extras(flags, expiry), key.as_bytes(), value),
+        Request::Replace{ref key, ref value, flags, expiry, noreply: _} => put_packet(buf, REQUEST, REPLACE, 0, opaque, 0, &storage_extras(flags, expiry), key.as_bytes(), value),
+        Request::Append{ref key, ref value, noreply: _} => put_packet(buf, REQUEST, APPEND, 0, opaque, 0, &[], key.as_bytes(), value),
+        Request::Prepend{ref key, ref value, noreply: _} => put_packet(buf, REQUEST, PREPEND, 0, opaque, 0, &[], key.as_bytes(), value),
+        Request::Cas{ref key, ref value, flags, expiry, cas, noreply: _} => put_packet(buf, REQUEST, SET, 0, opaque, cas, &storage_extras(flags, expiry), key.as_bytes(), value),
+        Request::Get{ref keys} |
+        Request::Gets{ref keys} => {
+            for key in keys.iter() {
+                put_packet(buf, REQUEST, GETKQ, 0, opaque, 0, &[], key.as_bytes(), &[]);
+            }
+            put_packet(buf, REQUEST, NOOP, 0, opaque, 0, &[], &[], &[]);
+        },
+        Request::Delete{ref key, noreply: _} => put_packet(buf, REQUEST, DELETE, 0, opaque, 0, &[], key.as_bytes(), &[]),
+        Request::Incr{ref key, value, noreply: _} => put_packet(buf, REQUEST, INCREMENT, 0, opaque, 0, &counter_extras(value), key.as_bytes(), &[]),
+        Request::Decr{ref key, value, noreply: _} => put_packet(buf, REQUEST, DECREMENT, 0, opaque, 0, &counter_extras(value), key.as_bytes(), &[]),
+        Request::Touch{ref key, expiry, noreply: _} => put_packet(buf, REQUEST, TOUCH, 0, opaque, 0, &expiry.to_be_bytes(), key.as_bytes(), &[]),
+        Request::FlushAll{delay: Some(delay), noreply: _} => put_packet(buf, REQUEST, FLUSH, 0, opaque, 0, &delay.to_be_bytes(), &[], &[]),
+        Request::FlushAll{delay: None, noreply: _} => put_packet(buf, REQUEST, FLUSH, 0, opaque, 0, &[], &[], &[]),
+        Request::Version => put_packet(buf, REQUEST, VERSION, 0, opaque, 0, &[], &[], &[]),
+    }
+    Ok(())
+}
+
+// Writes requests tagged with an opaque and reads response packets.  The
+// parts of a packet share the read buffer rather than being copied out of it.
+pub struct BinaryCodec {
+    max_value_len: usize,
+}
+
+impl BinaryCodec {
+    pub fn new() -> BinaryCodec {
+        BinaryCodec{max_value_len: MAX_VALUE_LEN}
+    }
+
+    // Sets the largest value a response may carry, past which it is refused
+    // with `InvalidData` before its body is read.
+    pub fn with_max_value_len(self, max_value_len: usize) -> BinaryCodec {
+        BinaryCodec{max_value_len: max_value_len}
+    }
+}
+
+impl Default for BinaryCodec {
+    fn default() -> BinaryCodec {
+        BinaryCodec::new()
+    }
+}
+
+impl Encoder<(u32, Request)> for BinaryCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, (opaque, req): (u32, Request), buf: &mut BytesMut) -> io::Result<()> {
+        build(&req, opaque, buf)
+    }
+}
+
+impl Decoder for BinaryCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Packet>, io::Error> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        if buf[0] != RESPONSE {
+            return Err(invalid_data("not a response packet"));
+        }
+        let mut header = &buf[..HEADER_LEN];
+        header.advance(2);
+        let key_len = header.get_u16() as usize;
+        let extras_len = header.get_u8() as usize;
+        header.advance(1);
+        let status = header.get_u16();
+        let body_len = header.get_u32() as usize;
+        let opaque = header.get_u32();
+        let cas = header.get_u64();
+        if extras_len + key_len > body_len {
+            return Err(invalid_data("packet body is shorter than its extras and key"));
+        }
+        if body_len - extras_len - key_len > self.max_value_len {
+            return Err(invalid_data(&format!("value of {} bytes is over the {} byte limit", body_len - extras_len - key_len, self.max_value_len)));
+        }
+        if buf.len() < HEADER_LEN + body_len {
+            buf.reserve(cmp::min(HEADER_LEN + body_len - buf.len(), READ_AHEAD));
+            return Ok(None);
+        }
+        let opcode = buf[1];
+        buf.advance(HEADER_LEN);
+        let extras = buf.split_to(extras_len).freeze();
+        let key = buf.split_to(key_len).freeze();
+        let value = buf.split_to(body_len - extras_len - key_len).freeze();
+        Ok(Some(Packet{opcode: opcode, status: status, opaque: opaque, cas: cas, extras: extras, key: key, value: value}))
+    }
+}
+
+// Frames a client connection with the memcached binary protocol.
+pub struct BinaryProto;
+
+impl BinaryProto {
+    pub fn client<T: AsyncRead + AsyncWrite>(&self, io: T) -> Framed<T, BinaryCodec> {
+        Framed::new(io, BinaryCodec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use std::io;
+    use tokio_util::codec::{Decoder, Encoder};
+    use crate::binary::{put_packet, BinaryCodec, Packet, RESPONSE, GETKQ, NOOP, INCREMENT, KEY_EXISTS, NO_ERROR, VALUE_TOO_LARGE};
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::value::Value;
+
+    #[test]
+    fn packets() {
+        let mut codec = BinaryCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode((7, Request::Get{keys: vec![String::from("a"), String::from("bc")]}), &mut buf).unwrap();
+        assert_eq!(buf.len(), 3 * 24 + 3);
+        assert_eq!(&buf[..2], &[0x80, GETKQ]);
+        assert_eq!(&buf[12..16], &7u32.to_be_bytes());
+        assert_eq!(&buf[2 * 24 + 3..2 * 24 + 5], &[0x80, NOOP]);
+        assert!(codec.encode((8, Request::Get{keys: vec![String::from("bad key")]}), &mut BytesMut::new()).is_err());
+
+        let mut buf = BytesMut::new();
+        put_packet(&mut buf, RESPONSE, GETKQ, NO_ERROR, 7, 9, &5u32.to_be_bytes(), b"a", b"value");
+        put_packet(&mut buf, RESPONSE, INCREMENT, NO_ERROR, 8, 0, &[], &[], &42u64.to_be_bytes());
+        put_packet(&mut buf, RESPONSE, 0x02, KEY_EXISTS, 9, 0, &[], &[], b"Data exists for key.");
+        let mut partial = buf.split_to(30);
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        let hit = codec.decode(&mut partial).unwrap().unwrap();
+        assert!(hit.is_value());
+        assert_eq!((hit.opaque, hit.cas), (7, 9));
+        assert_eq!(hit.to_value().unwrap(), Value{key: String::from("a"), value: Bytes::from_static(b"value"), flags: 5, cas: Some(9)});
+        let counter: Packet = codec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!(counter.to_response().unwrap(), Response::UpdatedValue(42));
+        assert_eq!(codec.decode(&mut partial).unwrap().unwrap().to_response().unwrap(), Response::NotStored);
+        assert!(partial.is_empty());
+
+        let mut buf = BytesMut::new();
+        put_packet(&mut buf, RESPONSE, GETKQ, VALUE_TOO_LARGE, 7, 0, &[], &[], b"Too large.");
+        let failed = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(!failed.is_value());
+        assert!(failed.is_value_error());
+        assert_eq!(failed.to_response().unwrap(), Response::ServerError(String::from("Too large.")));
+    }
+
+    #[test]
+    fn limits() {
+        let mut buf = BytesMut::new();
+        put_packet(&mut buf, RESPONSE, GETKQ, NO_ERROR, 1, 0, &[], b"a", &[]);
+        buf[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(BinaryCodec::new().decode(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut buf = BytesMut::new();
+        put_packet(&mut buf, RESPONSE, GETKQ, NO_ERROR, 1, 0, &[0; 4], b"a", &[0; 10]);
+        assert!(BinaryCodec::new().with_max_value_len(10).decode(&mut buf.clone()).unwrap().is_some());
+        assert_eq!(BinaryCodec::new().with_max_value_len(9).decode(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut buf = BytesMut::new();
+        put_packet(&mut buf, RESPONSE, GETKQ, NO_ERROR, 1, 0, &[], b"a", &[]);
+        buf[8..12].copy_from_slice(&1_000_000u32.to_be_bytes());
+        assert_eq!(BinaryCodec::new().decode(&mut buf).unwrap(), None);
+        assert!(buf.capacity() <= 128 * 1024);
+    }
+}
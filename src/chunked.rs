@@ -0,0 +1,289 @@
+use bytes::Bytes;
+use futures::future;
+use rand::{self, Rng};
+use std::cmp;
+use std::io;
+use std::sync::Arc;
+use std::str;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::value::Value;
+use crate::hash;
+use crate::key::{Key, MAX_KEY_LEN};
+use crate::service::{Service, BoxFuture};
+use crate::flags;
+
+// memcached's default item size limit is 1MB, including the key and item
+// header, so leave some room for those.
+const DEFAULT_CHUNK_SIZE: usize = 1000 * 1024;
+
+// The most chunks a value may be split into, which bounds the keys fetched
+// for a manifest read from the cache.
+const MAX_CHUNKS: usize = 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+struct Manifest {
+    id: u64,
+    chunks: usize,
+    len: usize,
+    hash: u64,
+    flags: u16,
+}
+
+impl Manifest {
+    fn parse(value: &[u8]) -> Option<Manifest> {
+        let value = match str::from_utf8(value) {
+            Ok(value) => value,
+            Err(_) => return None,
+        };
+        let fields: Vec<&str> = value.split(' ').collect();
+        if fields.len() != 5 {
+            return None;
+        }
+        let manifest = Manifest{
+            id: u64::from_str_radix(fields[0], 16).ok()?,
+            chunks: fields[1].parse().ok()?,
+            len: fields[2].parse().ok()?,
+            hash: u64::from_str_radix(fields[3], 16).ok()?,
+            flags: fields[4].parse().ok()?,
+        };
+        // Every chunk holds at least one byte.
+        if manifest.chunks > MAX_CHUNKS || manifest.chunks > manifest.len || (manifest.chunks == 0 && manifest.len != 0) {
+            return None;
+        }
+        Some(manifest)
+    }
+
+    fn build(&self) -> Vec<u8> {
+        format!("{:x} {} {} {:x} {}", self.id, self.chunks, self.len, self.hash, self.flags).into_bytes()
+    }
+
+    // Keys too long to take the suffix are replaced by their hash.  The
+    // manifest's hash of the value catches the unlikely collision.
+    fn chunk_key(&self, key: &str, index: usize) -> String {
+        let chunk_key = format!("{}#{:x}:{}", key, self.id, index);
+        if chunk_key.len() <= MAX_KEY_LEN {
+            return chunk_key;
+        }
+        format!("{:016x}#{:x}:{}", hash::fnv1a(key.as_bytes()), self.id, index)
+    }
+
+    // Reassembles the value from its chunks, or returns `None` if any chunk
+    // is missing or the result does not match the manifest.  The manifest
+    // comes from the cache, so nothing is allocated until the chunks are
+    // known to add up to the length it claims.
+    fn assemble(&self, key: &str, chunks: &mut Vec<Value>) -> Option<Vec<u8>> {
+        let mut parts = Vec::new();
+        for index in 0..self.chunks {
+            let chunk_key = self.chunk_key(key, index);
+            let position = chunks.iter().position(|chunk| chunk.key == chunk_key)?;
+            parts.push(chunks.swap_remove(position).value);
+        }
+        if parts.iter().map(|part| part.len()).sum::<usize>() != self.len {
+            return None;
+        }
+        let mut value = Vec::with_capacity(self.len);
+        for part in parts {
+            value.extend_from_slice(&part);
+        }
+        if hash::fnv1a(&value) == self.hash {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+// Splits values larger than the chunk size over several keys.  The chunks
+// are written first, under keys derived from the original key and a random
+// id, followed by a small manifest under the original key describing them.
+// Reads of a manifest fetch and check the chunks, and report a miss if any
+// have been evicted.  Deleting a chunked value only deletes the manifest; the
+// orphaned chunks are left to expire or be evicted.  Values needing more than
+// `MAX_CHUNKS` chunks are refused.
+pub struct Chunked<S> {
+    inner: Arc<S>,
+    chunk_size: usize,
+}
+
+impl<S> Chunked<S> {
+    pub fn new(inner: S) -> Chunked<S> {
+        Chunked::with_chunk_size(inner, DEFAULT_CHUNK_SIZE)
+    }
+
+    // A chunk size of zero is taken as one.
+    pub fn with_chunk_size(inner: S, chunk_size: usize) -> Chunked<S> {
+        Chunked{inner: Arc::new(inner), chunk_size: cmp::max(chunk_size, 1)}
+    }
+}
+
+impl<S> Chunked<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+          S::Future: Send {
+    // Writes the chunks of `value`, then sends `manifest_request` built from
+    // the manifest value and flags.
+    fn store<F>(&self, key: String, value: Bytes, flags: u16, expiry: u32, manifest_request: F) -> BoxFuture<Response>
+        where F: FnOnce(String, Bytes, u16) -> Request + Send + 'static {
+        // Checked before any chunks are written under a key derived from it.
+        if let Err(err) = Key::validate(&key) {
+            return Box::pin(future::err(err));
+        }
+        let chunks = value.len().div_ceil(self.chunk_size);
+        if chunks > MAX_CHUNKS {
+            return Box::pin(future::err(io::Error::new(io::ErrorKind::InvalidInput, format!("value of {} bytes needs more than {} chunks", value.len(), MAX_CHUNKS))));
+        }
+        let manifest = Manifest{
+            id: rand::thread_rng().gen(),
+            chunks: chunks,
+            len: value.len(),
+            hash: hash::fnv1a(&value),
+            flags: flags,
+        };
+        let writes: Vec<_> = (0..manifest.chunks).map(|index| {
+            let chunk = value.slice(index * self.chunk_size..cmp::min((index + 1) * self.chunk_size, value.len()));
+            self.inner.call(Request::Set{key: manifest.chunk_key(&key, index), value: chunk, flags: 0, expiry: expiry, noreply: false})
+        }).collect();
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            for rsp in future::join_all(writes).await {
+                match rsp? {
+                    Response::Stored => {},
+                    rsp => return Err(io::Error::other(format!("failed to store chunk: {:?}", rsp))),
+                }
+            }
+            inner.call(manifest_request(key, manifest.build().into(), flags::CHUNKED)).await
+        })
+    }
+
+    async fn load(inner: &S, values: Vec<Value>) -> io::Result<Response> {
+        let manifests: Vec<(usize, Manifest)> = values.iter().enumerate()
+            .filter(|&(_, value)| value.flags & flags::CHUNKED != 0)
+            .filter_map(|(index, value)| Manifest::parse(&value.value).map(|manifest| (index, manifest)))
+            .collect();
+        if manifests.is_empty() {
+            return Ok(Response::Values(values));
+        }
+        let mut keys = Vec::new();
+        for &(index, ref manifest) in manifests.iter() {
+            keys.extend((0..manifest.chunks).map(|chunk| manifest.chunk_key(&values[index].key, chunk)));
+        }
+        let mut chunks = match inner.call(Request::Get{keys: keys}).await? {
+            Response::Values(chunks) => chunks,
+            rsp => return Err(io::Error::other(format!("unexpected response {:?}", rsp))),
+        };
+        let mut assembled: Vec<Option<Value>> = values.into_iter().map(Some).collect();
+        for (index, manifest) in manifests {
+            let value = assembled[index].take().expect("manifest index out of range");
+            assembled[index] = manifest.assemble(&value.key, &mut chunks).map(|data| {
+                Value{value: data.into(), flags: manifest.flags, ..value}
+            });
+        }
+        Ok(Response::Values(assembled.into_iter().flatten().collect()))
+    }
+}
+
+impl<S> Service for Chunked<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+          S::Future: Send {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        match req {
+            Request::Set{key, value, flags, expiry, noreply} if value.len() > self.chunk_size => {
+                self.store(key, value, flags, expiry, move |key, value, flags| {
+                    Request::Set{key: key, value: value, flags: flags, expiry: expiry, noreply: noreply}
+                })
+            },
+            Request::Add{key, value, flags, expiry, noreply} if value.len() > self.chunk_size => {
+                self.store(key, value, flags, expiry, move |key, value, flags| {
+                    Request::Add{key: key, value: value, flags: flags, expiry: expiry, noreply: noreply}
+                })
+            },
+            Request::Replace{key, value, flags, expiry, noreply} if value.len() > self.chunk_size => {
+                self.store(key, value, flags, expiry, move |key, value, flags| {
+                    Request::Replace{key: key, value: value, flags: flags, expiry: expiry, noreply: noreply}
+                })
+            },
+            Request::Cas{key, value, flags, expiry, cas, noreply} if value.len() > self.chunk_size => {
+                self.store(key, value, flags, expiry, move |key, value, flags| {
+                    Request::Cas{key: key, value: value, flags: flags, expiry: expiry, cas: cas, noreply: noreply}
+                })
+            },
+            req @ Request::Get{..} | req @ Request::Gets{..} => {
+                let inner = self.inner.clone();
+                let rsp = self.inner.call(req);
+                Box::pin(async move {
+                    match rsp.await? {
+                        Response::Values(values) => Chunked::load(&*inner, values).await,
+                        rsp => Ok(rsp),
+                    }
+                })
+            },
+            req => Box::pin(self.inner.call(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::io;
+    use crate::api::Api;
+    use crate::value::Value;
+    use crate::chunked::{Chunked, Manifest, MAX_CHUNKS};
+    use crate::hash;
+    use crate::key::{Key, MAX_KEY_LEN};
+    use crate::memory::InMemory;
+
+    #[test]
+    fn assemble() {
+        let data = b"abcdefgh".to_vec();
+        let manifest = Manifest{id: 0x1f, chunks: 3, len: data.len(), hash: hash::fnv1a(&data), flags: 3};
+        assert_eq!(Some(&manifest), Manifest::parse(&manifest.build()).as_ref());
+        let chunks: Vec<Value> = data.chunks(3).enumerate().map(|(index, chunk)| {
+            Value{key: manifest.chunk_key("key", index), value: chunk.to_vec().into(), flags: 0, cas: None}
+        }).collect();
+        assert_eq!(Some(data.clone()), manifest.assemble("key", &mut chunks.clone()));
+        assert_eq!(None, manifest.assemble("key", &mut chunks[..2].to_vec()));
+        let mut corrupt = chunks.clone();
+        corrupt[1].value = Bytes::from_static(b"xyz");
+        assert_eq!(None, manifest.assemble("key", &mut corrupt));
+    }
+
+    #[test]
+    fn untrusted_manifest() {
+        let manifest = Manifest{id: 1, chunks: 1, len: usize::MAX, hash: 0, flags: 0};
+        let mut chunks = vec![Value{key: manifest.chunk_key("key", 0), value: Bytes::from_static(b"abc"), flags: 0, cas: None}];
+        assert_eq!(None, manifest.assemble("key", &mut chunks));
+
+        assert_eq!(None, Manifest::parse(b"1 18446744073709551615 3 0 0"));
+        assert_eq!(None, Manifest::parse(format!("1 {} 100000 0 0", MAX_CHUNKS + 1).as_bytes()));
+        assert_eq!(None, Manifest::parse(b"1 4 3 0 0"));
+        assert_eq!(None, Manifest::parse(b"1 0 3 0 0"));
+        assert!(Manifest::parse(b"1 0 0 0 0").is_some());
+        assert!(Manifest::parse(format!("1 {} 100000 0 0", MAX_CHUNKS).as_bytes()).is_some());
+    }
+
+    #[tokio::test]
+    async fn long_keys() {
+        let cache = InMemory::new();
+        let chunked = Chunked::with_chunk_size(cache.clone(), 0);
+        let key = "k".repeat(MAX_KEY_LEN);
+        chunked.set(key.clone(), Bytes::from_static(b"abc"), 5, 0).await.unwrap();
+        assert_eq!(cache.len(), 4);
+        let values = chunked.get(vec![key.clone()]).await.unwrap();
+        assert_eq!((&values[0].value[..], values[0].flags), (&b"abc"[..], 5));
+        let manifest = Manifest{id: u64::MAX, chunks: 1, len: 1, hash: 0, flags: 0};
+        assert!(Key::new(manifest.chunk_key(&key, usize::MAX)).is_ok());
+
+        let err = chunked.set(format!("{}k", key), Bytes::from_static(b"abc"), 0, 0).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(cache.len(), 4);
+        let err = chunked.set(String::from("big"), vec![0; MAX_CHUNKS + 1].into(), 0, 0).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
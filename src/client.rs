@@ -1,31 +1,226 @@
-use futures::{Future, Then, future};
-use futures::future::FutureResult;
-use tokio_core::net::TcpStream;
-use tokio_core::reactor::Handle;
-use tokio_proto::TcpClient;
-use tokio_proto::pipeline::ClientService;
-use tokio_service::Service;
+use bytes::Bytes;
+use futures::FutureExt;
+use futures::future::{self, Map};
+use futures::stream;
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::Framed;
+use std::collections::VecDeque;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
-use request::Request;
-use response::Response;
-use value::Value;
-use proto::Proto;
-use api::Api;
+use crate::request::Request;
+use crate::response::Response;
+use crate::value::Value;
+use crate::service::{Service, BoxFuture};
+use crate::proto::{Proto, ClientCodec, ClientFrame, ValueFrame, Streaming};
+use crate::stream::ValueStream;
+use crate::api::Api;
+use crate::metrics::{Counted, Metrics};
 
+// How many values, or chunks of a value, are read ahead of a streaming
+// reader.
+const READ_AHEAD: usize = 16;
+
+enum Pending {
+    Response(oneshot::Sender<io::Result<Response>>),
+    // A `get` whose values are streamed, each sent once it has been read.
+    Values(mpsc::Sender<io::Result<Value>>),
+    // A `get` whose values are streamed, each sent as it starts arriving.
+    Pieces(mpsc::Sender<io::Result<ValueStream>>),
+}
+
+impl Pending {
+    fn fail(self, err: io::Error) {
+        match self {
+            Pending::Response(tx) => {
+                let _ = tx.send(Err(err));
+            },
+            Pending::Values(tx) => {
+                let _ = tx.try_send(Err(err));
+            },
+            Pending::Pieces(tx) => {
+                let _ = tx.try_send(Err(err));
+            },
+        }
+    }
+}
+
+// A pipelined connection to a memcached server.  Requests are written as soon
+// as they are made and responses are matched to them in order by a task
+// spawned on the current runtime, which exits once every clone of the client
+// has been dropped.
+#[derive(Clone)]
 pub struct Client {
-    inner: ClientService<TcpStream, Proto>,
+    requests: mpsc::UnboundedSender<(Request, Pending)>,
 }
 
 impl Client {
-    pub fn connect(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
-        Box::new(
-            TcpClient::new(Proto)
-                .connect(addr, handle)
-                .map(|client_service| {
-                    Client{inner: client_service}
-                }))
+    pub async fn connect(addr: &SocketAddr) -> io::Result<Client> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(Proto.client(stream), rx));
+        Ok(Client{requests: tx})
+    }
+
+    // Connects as `connect` does, counting the connection and the bytes
+    // through it in `metrics`.  Per-command counts come from wrapping the
+    // client in a `MetricsLayer`.
+    pub async fn connect_with_metrics(addr: &SocketAddr, metrics: Arc<Metrics>) -> io::Result<Client> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(Proto.client(Counted::new(stream, metrics)), rx));
+        Ok(Client{requests: tx})
+    }
+
+    // Gets `key`, returning its body in chunks as they are read rather than
+    // buffering it whole, or `None` on a miss.  Only a few chunks are read
+    // ahead, and later responses on the connection wait behind the body, so
+    // it should be read promptly or dropped.
+    pub async fn get_stream(&self, key: String) -> io::Result<Option<ValueStream>> {
+        let req = Request::Get{keys: vec![key]};
+        req.validate()?;
+        let (tx, mut rx) = mpsc::channel(1);
+        if self.requests.send((req, Pending::Pieces(tx))).is_err() {
+            return Err(broken_pipe());
+        }
+        rx.recv().await.transpose()
+    }
+
+    // Gets `keys`, yielding each value as soon as it is read rather than once
+    // the whole response is in.  As with `get_stream`, later responses on the
+    // connection wait behind the values, so they should be read promptly or
+    // the stream dropped.
+    pub fn get_many_stream(&self, keys: Vec<String>) -> impl Stream<Item = io::Result<Value>> + Send + 'static {
+        let (tx, mut rx) = mpsc::channel(READ_AHEAD);
+        let req = Request::Get{keys: keys};
+        match req.validate() {
+            Ok(()) => {
+                if let Err(mpsc::error::SendError((_, pending))) = self.requests.send((req, Pending::Values(tx))) {
+                    pending.fail(broken_pipe());
+                }
+            },
+            Err(err) => {
+                let _ = tx.try_send(Err(err));
+            },
+        }
+        stream::poll_fn(move |cx| rx.poll_recv(cx))
+    }
+}
+
+fn broken_pipe() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "connection closed")
+}
+
+// Sets the codec to stream values if the next response is for a streamed
+// `get`.
+fn set_streaming<T>(transport: &mut Framed<T, ClientCodec>, pending: &VecDeque<Pending>) {
+    transport.codec_mut().set_streaming(match pending.front() {
+        Some(&Pending::Values(_)) => Streaming::Values,
+        Some(&Pending::Pieces(_)) => Streaming::Pieces,
+        _ => Streaming::Off,
+    });
+}
+
+async fn run<T>(mut transport: Framed<T, ClientCodec>, mut requests: mpsc::UnboundedReceiver<(Request, Pending)>)
+    where T: AsyncRead + AsyncWrite + Unpin {
+    let mut pending: VecDeque<Pending> = VecDeque::new();
+    // The body of the value being streamed, if its reader is still there.
+    let mut body: Option<mpsc::Sender<io::Result<Bytes>>> = None;
+    let mut open = true;
+    let failure = loop {
+        tokio::select! {
+            req = requests.recv(), if open => {
+                let (req, tx) = match req {
+                    Some(req) => req,
+                    None => {
+                        open = false;
+                        if pending.is_empty() {
+                            break None;
+                        }
+                        continue;
+                    },
+                };
+                // Write every request that is already queued before flushing.
+                let mut next = Some((req, tx));
+                while let Some((req, tx)) = next.take() {
+                    if let Err(err) = transport.feed(req).await {
+                        tx.fail(err);
+                        break;
+                    }
+                    pending.push_back(tx);
+                    next = requests.try_recv().ok();
+                }
+                set_streaming(&mut transport, &pending);
+                if let Err(err) = transport.flush().await {
+                    break Some(err);
+                }
+            },
+            frame = transport.next(), if !pending.is_empty() => {
+                match frame {
+                    Some(Ok(ClientFrame::Response(rsp))) => {
+                        match pending.pop_front() {
+                            Some(Pending::Response(tx)) => {
+                                let _ = tx.send(Ok(rsp));
+                            },
+                            Some(Pending::Values(tx)) if !matches!(rsp, Response::Values(_)) => {
+                                let _ = tx.send(Err(error(Ok(rsp)))).await;
+                            },
+                            Some(Pending::Pieces(tx)) if !matches!(rsp, Response::Values(_)) => {
+                                let _ = tx.send(Err(error(Ok(rsp)))).await;
+                            },
+                            _ => {},
+                        }
+                        set_streaming(&mut transport, &pending);
+                        if !open && pending.is_empty() {
+                            break None;
+                        }
+                    },
+                    Some(Ok(ClientFrame::Value(value))) => {
+                        if let Some(Pending::Values(tx)) = pending.front() {
+                            let _ = tx.send(Ok(value)).await;
+                        }
+                    },
+                    Some(Ok(ClientFrame::Piece(ValueFrame::Header{key, flags, len, cas}))) => {
+                        let (tx, mut rx) = mpsc::channel(READ_AHEAD);
+                        let value = ValueStream::new(key, flags, cas, len, stream::poll_fn(move |cx| rx.poll_recv(cx)));
+                        body = None;
+                        if let Some(Pending::Pieces(values)) = pending.front() {
+                            if values.send(Ok(value)).await.is_ok() {
+                                body = Some(tx);
+                            }
+                        }
+                    },
+                    Some(Ok(ClientFrame::Piece(ValueFrame::Chunk(chunk)))) => {
+                        // Waiting for the reader to make room holds back the
+                        // rest of the connection, which bounds what is buffered.
+                        if let Some(ref tx) = body {
+                            if tx.send(Ok(chunk)).await.is_err() {
+                                body = None;
+                            }
+                        }
+                    },
+                    Some(Ok(ClientFrame::Piece(ValueFrame::End))) => body = None,
+                    Some(Err(err)) => break Some(err),
+                    None => break None,
+                }
+            },
+        }
+    };
+    let error = || match failure {
+        Some(ref err) => io::Error::new(err.kind(), err.to_string()),
+        None => broken_pipe(),
+    };
+    if let Some(tx) = body {
+        let _ = tx.try_send(Err(error()));
+    }
+    for tx in pending {
+        tx.fail(error());
     }
 }
 
@@ -33,171 +228,199 @@ impl Service for Client {
     type Request = Request;
     type Response = Response;
     type Error = io::Error;
-    type Future = Box<Future<Item = Response, Error = io::Error>>;
+    type Future = BoxFuture<Response>;
 
     fn call(&self, req: Request) -> Self::Future {
-        Box::new(self.inner.call(req))
+        // Caught here as well as in the codec, since an encoding error there
+        // takes the whole connection down with it.
+        if let Err(err) = req.validate() {
+            return Box::pin(future::err(err));
+        }
+        let (tx, rx) = oneshot::channel();
+        if self.requests.send((req, Pending::Response(tx))).is_err() {
+            return Box::pin(future::err(broken_pipe()));
+        }
+        Box::pin(rx.map(|result| result.unwrap_or_else(|_| Err(broken_pipe()))))
+    }
+}
+
+// Turns an unexpected response into an error, keeping `EXISTS` and
+// `NOT_FOUND` distinguishable by their error kinds.
+fn error(result: Result<Response, io::Error>) -> io::Error {
+    match result {
+        Ok(Response::Exists) => io::Error::new(io::ErrorKind::AlreadyExists, "exists"),
+        Ok(Response::NotFound) => io::Error::new(io::ErrorKind::NotFound, "not found"),
+        Ok(Response::NotStored) => io::Error::other("not stored"),
+        Ok(Response::Error) => io::Error::other("error"),
+        Ok(Response::ClientError(message)) => io::Error::new(io::ErrorKind::InvalidInput, message),
+        Ok(Response::ServerError(message)) => io::Error::other(message),
+        Ok(rsp) => io::Error::new(io::ErrorKind::InvalidData, format!("unexpected response {:?}", rsp)),
+        Err(err) => err,
     }
 }
 
 impl<T: Service<Request = Request, Response = Response, Error = io::Error>> Api<io::Error> for T
-    where T::Future: Future<Item = Response, Error = io::Error> + Sized {
-    type FutureUnit = Then<T::Future, FutureResult<(), io::Error>, fn(Result<Response, io::Error>) -> FutureResult<(), io::Error>>;
-    type FutureValues = Then<T::Future, FutureResult<Vec<Value>, io::Error>, fn(Result<Response, io::Error>) -> FutureResult<Vec<Value>, io::Error>>;
-    type FutureU64 = Then<T::Future, FutureResult<u64, io::Error>, fn(Result<Response, io::Error>) -> FutureResult<u64, io::Error>>;
-    type FutureString = Then<T::Future, FutureResult<String, io::Error>, fn(Result<Response, io::Error>) -> FutureResult<String, io::Error>>;
-
-    fn set(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32) -> Self::FutureUnit {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<(), io::Error> {
-            future::result(match result {
+    where T::Future: Send + 'static {
+    type FutureUnit = Map<T::Future, fn(Result<Response, io::Error>) -> io::Result<()>>;
+    type FutureValues = Map<T::Future, fn(Result<Response, io::Error>) -> io::Result<Vec<Value>>>;
+    type FutureU64 = Map<T::Future, fn(Result<Response, io::Error>) -> io::Result<u64>>;
+    type FutureString = Map<T::Future, fn(Result<Response, io::Error>) -> io::Result<String>>;
+
+    fn set(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<()> {
+            match result {
                 Ok(Response::Stored) => Ok(()),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Set{key: key, value: value, flags: flags, expiry: expiry, noreply: false})
-            .then(map_result)
+            .map(map_result)
     }
 
-    fn add(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32) -> Self::FutureUnit {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<(), io::Error> {
-            future::result(match result {
+    fn add(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<()> {
+            match result {
                 Ok(Response::Stored) => Ok(()),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                Ok(Response::NotStored) => Err(io::Error::new(io::ErrorKind::AlreadyExists, "not stored")),
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Add{key: key, value: value, flags: flags, expiry: expiry, noreply: false})
-            .then(map_result)
+            .map(map_result)
     }
 
-    fn replace(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32) -> Self::FutureUnit {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<(), io::Error> {
-            future::result(match result {
+    fn replace(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<()> {
+            match result {
                 Ok(Response::Stored) => Ok(()),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                Ok(Response::NotStored) => Err(io::Error::new(io::ErrorKind::NotFound, "not stored")),
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Replace{key: key, value: value, flags: flags, expiry: expiry, noreply: false})
-            .then(map_result)
+            .map(map_result)
     }
 
-    fn append(&self, key: String, value: Vec<u8>) -> Self::FutureUnit {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<(), io::Error> {
-            future::result(match result {
+    fn append(&self, key: String, value: Bytes) -> Self::FutureUnit {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<()> {
+            match result {
                 Ok(Response::Stored) => Ok(()),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                Ok(Response::NotStored) => Err(io::Error::new(io::ErrorKind::NotFound, "not stored")),
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Append{key: key, value: value, noreply: false})
-            .then(map_result)
+            .map(map_result)
     }
 
-    fn prepend(&self, key: String, value: Vec<u8>) -> Self::FutureUnit {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<(), io::Error> {
-            future::result(match result {
+    fn prepend(&self, key: String, value: Bytes) -> Self::FutureUnit {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<()> {
+            match result {
                 Ok(Response::Stored) => Ok(()),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                Ok(Response::NotStored) => Err(io::Error::new(io::ErrorKind::NotFound, "not stored")),
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Prepend{key: key, value: value, noreply: false})
-            .then(map_result)
+            .map(map_result)
     }
 
-    fn cas(&self, key: String, value: Vec<u8>, flags: u16, expiry: u32, cas: u64) -> Self::FutureUnit {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<(), io::Error> {
-            future::result(match result {
+    fn cas(&self, key: String, value: Bytes, flags: u16, expiry: u32, cas: u64) -> Self::FutureUnit {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<()> {
+            match result {
                 Ok(Response::Stored) => Ok(()),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Cas{key: key, value: value, flags: flags, expiry: expiry, cas: cas, noreply: false})
-            .then(map_result)
+            .map(map_result)
     }
 
     fn get(&self, keys: Vec<String>) -> Self::FutureValues {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<Vec<Value>, io::Error> {
-            future::result(match result {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<Vec<Value>> {
+            match result {
                 Ok(Response::Values(values)) => Ok(values),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Get{keys: keys})
-            .then(map_result)
+            .map(map_result)
     }
 
     fn gets(&self, keys: Vec<String>) -> Self::FutureValues {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<Vec<Value>, io::Error> {
-            future::result(match result {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<Vec<Value>> {
+            match result {
                 Ok(Response::Values(values)) => Ok(values),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Gets{keys: keys})
-            .then(map_result)
+            .map(map_result)
     }
 
     fn delete(&self, key: String) -> Self::FutureUnit {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<(), io::Error> {
-            future::result(match result {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<()> {
+            match result {
                 Ok(Response::Deleted) => Ok(()),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Delete{key: key, noreply: false})
-            .then(map_result)
+            .map(map_result)
     }
 
     fn incr(&self, key: String, value: u64) -> Self::FutureU64 {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<u64, io::Error> {
-            future::result(match result {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<u64> {
+            match result {
                 Ok(Response::UpdatedValue(value)) => Ok(value),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Incr{key: key, value: value, noreply: false})
-            .then(map_result)
+            .map(map_result)
     }
 
     fn decr(&self, key: String, value: u64) -> Self::FutureU64 {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<u64, io::Error> {
-            future::result(match result {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<u64> {
+            match result {
                 Ok(Response::UpdatedValue(value)) => Ok(value),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                result => Err(error(result)),
+            }
         }
-        self.call(Request::Incr{key: key, value: value, noreply: false})
-            .then(map_result)
+        self.call(Request::Decr{key: key, value: value, noreply: false})
+            .map(map_result)
     }
 
     fn touch(&self, key: String, expiry: u32) -> Self::FutureUnit {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<(), io::Error> {
-            future::result(match result {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<()> {
+            match result {
                 Ok(Response::Touched) => Ok(()),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Touch{key: key, expiry: expiry, noreply: false})
-            .then(map_result)
+            .map(map_result)
     }
 
     fn flush_all(&self, delay: u32) -> Self::FutureUnit {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<(), io::Error> {
-            future::result(match result {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<()> {
+            match result {
                 Ok(Response::Ok) => Ok(()),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                result => Err(error(result)),
+            }
         }
         self.call(Request::FlushAll{delay: Some(delay), noreply: false})
-            .then(map_result)
+            .map(map_result)
     }
 
     fn version(&self) -> Self::FutureString {
-        fn map_result(result: Result<Response, io::Error>) -> FutureResult<String, io::Error> {
-            future::result(match result {
+        fn map_result(result: Result<Response, io::Error>) -> io::Result<String> {
+            match result {
                 Ok(Response::Version(version)) => Ok(version),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "eek"))
-            })
+                result => Err(error(result)),
+            }
         }
         self.call(Request::Version)
-            .then(map_result)
+            .map(map_result)
     }
 }
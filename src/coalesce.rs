@@ -0,0 +1,256 @@
+use futures::{FutureExt, future};
+use tokio::sync::oneshot;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use crate::key::Key;
+use crate::request::Request;
+use crate::response::Response;
+use crate::value::Value;
+use crate::service::{Service, BoxFuture};
+
+// The most keys sent in one batch.  A full batch is sent straight away.
+const MAX_BATCH: usize = 100;
+
+type Waiters = Arc<Mutex<Vec<oneshot::Sender<Result<Response, io::Error>>>>>;
+
+struct Inner<S> {
+    service: S,
+    // In-flight single-key reads, keyed by whether they are `gets` and key.
+    in_flight: Mutex<HashMap<(bool, String), Waiters>>,
+    batching: bool,
+    // Keys waiting to be sent in the next batch, for `get` and `gets`.
+    batches: Mutex<[Vec<(String, Waiters)>; 2]>,
+}
+
+// Shares one request between concurrent single-key `get`s (or `gets`) of the
+// same key.  With batching enabled, single-key reads issued before the first
+// of them has been sent are also combined into one multi-key request, of at
+// most `MAX_BATCH` keys.  Keys are validated first, so an invalid one fails
+// only its own caller.  A write to a key detaches it from any read already
+// in flight, so later reads see the write.
+pub struct Coalescing<S> {
+    inner: Arc<Inner<S>>,
+}
+
+impl<S> Coalescing<S> {
+    pub fn new(service: S) -> Coalescing<S> {
+        Coalescing::build(service, false)
+    }
+
+    pub fn with_batching(service: S) -> Coalescing<S> {
+        Coalescing::build(service, true)
+    }
+
+    fn build(service: S, batching: bool) -> Coalescing<S> {
+        Coalescing{
+            inner: Arc::new(Inner{
+                service: service,
+                in_flight: Mutex::new(HashMap::new()),
+                batching: batching,
+                batches: Mutex::new([Vec::new(), Vec::new()]),
+            }),
+        }
+    }
+}
+
+impl<S> Inner<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+          S::Future: Send {
+    fn send(inner: &Arc<Inner<S>>, cas: bool, keys: Vec<(String, Waiters)>) {
+        let req = {
+            let keys = keys.iter().map(|(key, _)| key.clone()).collect();
+            if cas { Request::Gets{keys: keys} } else { Request::Get{keys: keys} }
+        };
+        // Spawned rather than driven by the first caller, so that the other
+        // waiters are still answered if the first caller goes away.
+        let inner = inner.clone();
+        tokio::spawn(inner.service.call(req).map(move |result| {
+            inner.complete(cas, keys, result);
+        }));
+    }
+
+    fn complete(&self, cas: bool, keys: Vec<(String, Waiters)>, result: Result<Response, io::Error>) {
+        let mut values: HashMap<String, Value> = HashMap::new();
+        let mut failure = None;
+        match result {
+            Ok(Response::Values(found)) => {
+                values.extend(found.into_iter().map(|value| (value.key.clone(), value)));
+            },
+            Ok(rsp) => failure = Some(Ok(rsp)),
+            Err(err) => failure = Some(Err((err.kind(), err.to_string()))),
+        }
+        let mut in_flight = self.in_flight.lock().unwrap();
+        for (key, waiters) in keys {
+            let id = (cas, key.clone());
+            if in_flight.get(&id).is_some_and(|current| Arc::ptr_eq(current, &waiters)) {
+                in_flight.remove(&id);
+            }
+            for tx in mem::take(&mut *waiters.lock().unwrap()) {
+                let _ = tx.send(match failure {
+                    Some(Ok(ref rsp)) => Ok(rsp.clone()),
+                    Some(Err((kind, ref message))) => Err(io::Error::new(kind, message.clone())),
+                    None => Ok(Response::Values(values.get(&key).into_iter().cloned().collect())),
+                });
+            }
+        }
+    }
+}
+
+impl<S> Service for Coalescing<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+          S::Future: Send {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let (cas, key) = match req {
+            Request::Get{ref keys} if keys.len() == 1 => (false, keys[0].clone()),
+            Request::Gets{ref keys} if keys.len() == 1 => (true, keys[0].clone()),
+            req => {
+                if let Some(key) = req.key() {
+                    let mut in_flight = self.inner.in_flight.lock().unwrap();
+                    in_flight.remove(&(false, String::from(key)));
+                    in_flight.remove(&(true, String::from(key)));
+                }
+                return Box::pin(self.inner.service.call(req));
+            },
+        };
+        // Checked before the key is shared with other callers, whose reads
+        // would otherwise fail along with it.
+        if let Err(err) = Key::validate(&key) {
+            return Box::pin(future::err(err));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let rx: BoxFuture<Response> = Box::pin(rx.map(|result| {
+            match result {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::other("request abandoned")),
+            }
+        }));
+        let waiters = {
+            let mut in_flight = self.inner.in_flight.lock().unwrap();
+            if let Some(waiters) = in_flight.get(&(cas, key.clone())) {
+                waiters.lock().unwrap().push(tx);
+                return rx;
+            }
+            let waiters = Arc::new(Mutex::new(vec![tx]));
+            in_flight.insert((cas, key.clone()), waiters.clone());
+            waiters
+        };
+
+        if !self.inner.batching {
+            Inner::send(&self.inner, cas, vec![(key, waiters)]);
+            return rx;
+        }
+        let (first, full) = {
+            let mut batches = self.inner.batches.lock().unwrap();
+            let batch = &mut batches[cas as usize];
+            batch.push((key, waiters));
+            if batch.len() >= MAX_BATCH {
+                (false, Some(mem::take(batch)))
+            } else {
+                (batch.len() == 1, None)
+            }
+        };
+        if let Some(keys) = full {
+            Inner::send(&self.inner, cas, keys);
+        } else if first {
+            // Sent from a task that first yields to the scheduler, giving
+            // other reads made meanwhile the chance to join the batch.
+            let inner = self.inner.clone();
+            tokio::spawn(async move {
+                tokio::task::yield_now().await;
+                // Empty if the batch filled up and was sent already.
+                let keys = mem::take(&mut inner.batches.lock().unwrap()[cas as usize]);
+                if !keys.is_empty() {
+                    Inner::send(&inner, cas, keys);
+                }
+            });
+        }
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+    use std::io;
+    use std::time::Duration;
+    use crate::api::Api;
+    use crate::coalesce::Coalescing;
+    use crate::memory::InMemory;
+    use crate::mock::{Expectation, MockService};
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::value::Value;
+
+    fn value(key: &str) -> Value {
+        Value{key: String::from(key), value: Bytes::copy_from_slice(key.as_bytes()), flags: 0, cas: None}
+    }
+
+    #[tokio::test]
+    async fn shared() {
+        let mock = MockService::new().with_fallback(InMemory::new());
+        mock.expect(Expectation::new(Request::Get{keys: vec![String::from("a")]}).respond(Response::Values(vec![value("a")])).after(Duration::from_millis(20)));
+        let service = Coalescing::new(mock.clone());
+        let results = future::join_all((0..4).map(|_| service.get(vec![String::from("a")]))).await;
+        assert!(results.iter().all(|result| result.as_ref().unwrap() == &vec![value("a")]));
+        mock.verify();
+        assert_eq!(mock.calls().len(), 1);
+
+        // Once answered, the next read goes to the backend again.
+        assert!(service.get(vec![String::from("a")]).await.unwrap().is_empty());
+        assert_eq!(mock.calls().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn shared_error() {
+        let mock = MockService::new();
+        mock.expect(Expectation::any().fail(io::ErrorKind::ConnectionReset, "reset").after(Duration::from_millis(20)));
+        let service = Coalescing::new(mock.clone());
+        let results = future::join_all((0..4).map(|_| service.get(vec![String::from("a")]))).await;
+        assert!(results.iter().all(|result| result.as_ref().unwrap_err().kind() == io::ErrorKind::ConnectionReset));
+        assert_eq!(mock.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn batching() {
+        let mock = MockService::new();
+        mock.expect(Expectation::any().respond(Response::Values(vec![value("a"), value("c")])));
+        let service = Coalescing::with_batching(mock.clone());
+        let keys = ["a", "b", "c", "a"];
+        let results = future::join_all(keys.iter().map(|key| service.get(vec![String::from(*key)]))).await;
+        assert_eq!(results[0].as_ref().unwrap(), &vec![value("a")]);
+        assert!(results[1].as_ref().unwrap().is_empty());
+        assert_eq!(results[2].as_ref().unwrap(), &vec![value("c")]);
+        assert_eq!(results[3].as_ref().unwrap(), &vec![value("a")]);
+        mock.verify();
+        assert_eq!(mock.calls(), vec![Request::Get{keys: vec![String::from("a"), String::from("b"), String::from("c")]}]);
+    }
+
+    #[tokio::test]
+    async fn batch_errors_and_size() {
+        let mock = MockService::new().with_fallback(InMemory::new());
+        mock.set(String::from("good"), Bytes::from_static(b"1"), 0, 0).await.unwrap();
+        let service = Coalescing::with_batching(mock.clone());
+        let (good, bad) = future::join(service.get(vec![String::from("good")]), service.get(vec![String::from("bad key")])).await;
+        assert_eq!(&good.unwrap()[0].value[..], b"1");
+        assert_eq!(bad.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(mock.calls()[1], Request::Get{keys: vec![String::from("good")]});
+
+        let results = future::join_all((0..250).map(|i| service.get(vec![i.to_string()]))).await;
+        assert!(results.iter().all(|result| result.as_ref().unwrap().is_empty()));
+        let sizes: Vec<usize> = mock.calls()[2..].iter().map(|req| match *req {
+            Request::Get{ref keys} => keys.len(),
+            _ => 0,
+        }).collect();
+        assert_eq!(sizes, vec![100, 100, 50]);
+    }
+}
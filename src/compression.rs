@@ -0,0 +1,332 @@
+use bytes::Bytes;
+use futures::{FutureExt, future};
+use std::io;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::value::Value;
+use crate::flags;
+use crate::proto::MAX_VALUE_LEN;
+use crate::service::{Service, BoxFuture};
+
+const DEFAULT_THRESHOLD: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[cfg(feature = "zlib")]
+    Zlib,
+    #[cfg(feature = "lz4")]
+    Lz4,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+// Which client library's flag bits to use.  The pylibmc convention only
+// supports zlib, and its flag bit overlaps the type bits used by `Format`,
+// so values whose flags already set it (such as bincode) are refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagConvention {
+    PhpMemcached,
+    Pylibmc,
+}
+
+impl Compression {
+    fn flags(&self) -> u16 {
+        match *self {
+            #[cfg(feature = "zlib")]
+            Compression::Zlib => flags::COMPRESSION_ZLIB,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => flags::COMPRESSION_LZ4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => flags::COMPRESSION_ZSTD,
+        }
+    }
+
+    fn compress(&self, value: &[u8]) -> io::Result<Vec<u8>> {
+        // Unused when no codec is enabled.
+        let _ = value;
+        match *self {
+            #[cfg(feature = "zlib")]
+            Compression::Zlib => {
+                use std::io::Write;
+                let mut encoder = ::flate2::write::ZlibEncoder::new(Vec::new(), ::flate2::Compression::default());
+                encoder.write_all(value)?;
+                encoder.finish()
+            },
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(::lz4_flex::block::compress(value)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => ::zstd::bulk::compress(value, 0),
+        }
+    }
+
+    // Decompresses `value` to at most `max` bytes.  `len` is the length
+    // stored with the value, if any, and is only trusted up to `max`.
+    fn decompress(&self, value: &[u8], len: Option<usize>, max: usize) -> io::Result<Vec<u8>> {
+        if let Some(len) = len {
+            if len > max {
+                return Err(too_long(len, max));
+            }
+        }
+        // Unused when no codec is enabled.
+        let _ = value;
+        match *self {
+            #[cfg(feature = "zlib")]
+            Compression::Zlib => {
+                use std::cmp;
+                use std::io::Read;
+                let capacity = len.unwrap_or_else(|| value.len().saturating_mul(4));
+                let mut decompressed = Vec::with_capacity(cmp::min(capacity, max));
+                ::flate2::read::ZlibDecoder::new(value).take(max as u64 + 1).read_to_end(&mut decompressed)?;
+                if decompressed.len() > max {
+                    return Err(too_long(decompressed.len(), max));
+                }
+                Ok(decompressed)
+            },
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let len = len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "lz4 value without length"))?;
+                ::lz4_flex::block::decompress(value, len).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            },
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let len = len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "zstd value without length"))?;
+                ::zstd::bulk::decompress(value, len)
+            },
+        }
+    }
+}
+
+fn too_long(len: usize, max: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("decompressed value of {} bytes is over the {} byte limit", len, max))
+}
+
+impl FlagConvention {
+    fn compression(&self, flags: u16) -> io::Result<Option<Compression>> {
+        match *self {
+            FlagConvention::PhpMemcached => {
+                if flags & flags::COMPRESSED == 0 {
+                    return Ok(None);
+                }
+                match flags & (flags::COMPRESSION_MASK & !flags::COMPRESSED) {
+                    #[cfg(feature = "zlib")]
+                    flags::COMPRESSION_ZLIB => Ok(Some(Compression::Zlib)),
+                    #[cfg(feature = "lz4")]
+                    flags::COMPRESSION_LZ4 => Ok(Some(Compression::Lz4)),
+                    #[cfg(feature = "zstd")]
+                    flags::COMPRESSION_ZSTD => Ok(Some(Compression::Zstd)),
+                    _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported compression (flags {})", flags))),
+                }
+            },
+            FlagConvention::Pylibmc => {
+                if flags & flags::PYLIBMC_ZLIB == 0 {
+                    return Ok(None);
+                }
+                #[cfg(feature = "zlib")]
+                return Ok(Some(Compression::Zlib));
+                #[cfg(not(feature = "zlib"))]
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "zlib support not enabled"));
+            },
+        }
+    }
+
+    fn mask(&self) -> u16 {
+        match *self {
+            FlagConvention::PhpMemcached => flags::COMPRESSION_MASK,
+            FlagConvention::Pylibmc => flags::PYLIBMC_ZLIB,
+        }
+    }
+}
+
+// Compresses values of at least `threshold` bytes on the way out, and
+// decompresses values flagged as compressed on the way back.  Values that do
+// not shrink are stored as they are.  `append` and `prepend` are passed
+// through untouched, so should not be used on keys holding compressed values.
+// Values that would decompress to more than `max_value_len` bytes are
+// refused with `InvalidData`.
+pub struct Compressed<S> {
+    inner: S,
+    compression: Compression,
+    convention: FlagConvention,
+    threshold: usize,
+    max_value_len: usize,
+}
+
+impl<S> Compressed<S> {
+    pub fn new(inner: S, compression: Compression) -> Compressed<S> {
+        Compressed{
+            inner: inner,
+            compression: compression,
+            convention: FlagConvention::PhpMemcached,
+            threshold: DEFAULT_THRESHOLD,
+            max_value_len: MAX_VALUE_LEN,
+        }
+    }
+
+    pub fn with_threshold(self, threshold: usize) -> Compressed<S> {
+        Compressed{threshold: threshold, ..self}
+    }
+
+    pub fn with_convention(self, convention: FlagConvention) -> Compressed<S> {
+        Compressed{convention: convention, ..self}
+    }
+
+    pub fn with_max_value_len(self, max_value_len: usize) -> Compressed<S> {
+        Compressed{max_value_len: max_value_len, ..self}
+    }
+
+    fn compress(&self, value: Bytes, flags: u16) -> io::Result<(Bytes, u16)> {
+        // Such a value would read back as compressed whether it was or not.
+        if self.convention == FlagConvention::Pylibmc && flags & flags::PYLIBMC_ZLIB != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("flags {} overlap pylibmc's compression flag", flags)));
+        }
+        if value.len() < self.threshold {
+            return Ok((value, flags));
+        }
+        let compressed = self.compression.compress(&value)?;
+        match self.convention {
+            FlagConvention::PhpMemcached if compressed.len() + 4 < value.len() => {
+                let len = value.len() as u32;
+                let mut prefixed = Vec::with_capacity(compressed.len() + 4);
+                prefixed.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+                prefixed.extend_from_slice(&compressed);
+                Ok((prefixed.into(), flags | flags::COMPRESSED | self.compression.flags()))
+            },
+            FlagConvention::Pylibmc if compressed.len() < value.len() => {
+                Ok((compressed.into(), flags | flags::PYLIBMC_ZLIB))
+            },
+            _ => Ok((value, flags)),
+        }
+    }
+
+    fn decompress(convention: FlagConvention, max_value_len: usize, value: Value) -> io::Result<Value> {
+        let compression = match convention.compression(value.flags)? {
+            Some(compression) => compression,
+            None => return Ok(value),
+        };
+        let decompressed = match convention {
+            FlagConvention::PhpMemcached => {
+                if value.value.len() < 4 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is truncated", value.key)));
+                }
+                let len = value.value[..4].iter().rev().fold(0usize, |len, byte| (len << 8) | *byte as usize);
+                compression.decompress(&value.value[4..], Some(len), max_value_len)?
+            },
+            FlagConvention::Pylibmc => compression.decompress(&value.value, None, max_value_len)?,
+        };
+        Ok(Value{value: decompressed.into(), flags: value.flags & !convention.mask(), ..value})
+    }
+}
+
+impl<S> Service for Compressed<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error>,
+          S::Future: Send + 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let req = match req {
+            Request::Set{key, value, flags, expiry, noreply} => {
+                self.compress(value, flags).map(|(value, flags)| {
+                    Request::Set{key: key, value: value, flags: flags, expiry: expiry, noreply: noreply}
+                })
+            },
+            Request::Add{key, value, flags, expiry, noreply} => {
+                self.compress(value, flags).map(|(value, flags)| {
+                    Request::Add{key: key, value: value, flags: flags, expiry: expiry, noreply: noreply}
+                })
+            },
+            Request::Replace{key, value, flags, expiry, noreply} => {
+                self.compress(value, flags).map(|(value, flags)| {
+                    Request::Replace{key: key, value: value, flags: flags, expiry: expiry, noreply: noreply}
+                })
+            },
+            Request::Cas{key, value, flags, expiry, cas, noreply} => {
+                self.compress(value, flags).map(|(value, flags)| {
+                    Request::Cas{key: key, value: value, flags: flags, expiry: expiry, cas: cas, noreply: noreply}
+                })
+            },
+            req => Ok(req),
+        };
+        let req = match req {
+            Ok(req) => req,
+            Err(err) => return Box::pin(future::err(err)),
+        };
+        let convention = self.convention;
+        let max_value_len = self.max_value_len;
+        Box::pin(self.inner.call(req).map(move |rsp| {
+            match rsp? {
+                Response::Values(values) => {
+                    values.into_iter()
+                        .map(|value| Compressed::<S>::decompress(convention, max_value_len, value))
+                        .collect::<io::Result<Vec<Value>>>()
+                        .map(Response::Values)
+                },
+                rsp => Ok(rsp),
+            }
+        }))
+    }
+}
+
+#[cfg(all(test, feature = "zlib"))]
+mod tests {
+    use std::io;
+    use crate::value::Value;
+    use crate::compression::{Compressed, Compression, FlagConvention};
+    use crate::flags;
+    use crate::proto::MAX_VALUE_LEN;
+
+    #[test]
+    fn round_trip() {
+        let original = vec![b'a'; 10000];
+        for &convention in &[FlagConvention::PhpMemcached, FlagConvention::Pylibmc] {
+            let compressed = Compressed::new((), Compression::Zlib).with_convention(convention);
+            let (value, flags) = compressed.compress(original.clone().into(), 6).unwrap();
+            assert!(value.len() < original.len());
+            assert!(flags != 6);
+            let value = Value{key: String::from("key"), value: value, flags: flags, cas: None};
+            let value = Compressed::<()>::decompress(convention, MAX_VALUE_LEN, value).unwrap();
+            assert_eq!(&original[..], &value.value[..]);
+            assert_eq!(6, value.flags);
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let original = vec![b'a'; 10000];
+        for &convention in &[FlagConvention::PhpMemcached, FlagConvention::Pylibmc] {
+            let compressed = Compressed::new((), Compression::Zlib).with_convention(convention);
+            let (value, flags) = compressed.compress(original.clone().into(), 0).unwrap();
+            let value = Value{key: String::from("key"), value: value, flags: flags, cas: None};
+            assert!(Compressed::<()>::decompress(convention, 10000, value.clone()).is_ok());
+            let err = Compressed::<()>::decompress(convention, 9999, value).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // A length prefix claiming more than the limit is refused up front.
+        let compressed = Compressed::new((), Compression::Zlib);
+        let (value, flags) = compressed.compress(original.clone().into(), 0).unwrap();
+        let mut value = value.to_vec();
+        value[..4].copy_from_slice(&[0xff; 4]);
+        let value = Value{key: String::from("key"), value: value.into(), flags: flags, cas: None};
+        let err = Compressed::<()>::decompress(FlagConvention::PhpMemcached, MAX_VALUE_LEN, value).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn pylibmc_flags() {
+        let compressed = Compressed::new((), Compression::Zlib).with_convention(FlagConvention::Pylibmc);
+        for &len in &[10, 10000] {
+            let err = compressed.compress(vec![b'a'; len].into(), flags::PYLIBMC_ZLIB).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        #[cfg(feature = "bincode")]
+        assert!(compressed.compress(vec![b'a'; 10].into(), crate::typed::Format::Bincode.flags()).is_err());
+        #[cfg(feature = "json")]
+        assert!(compressed.compress(vec![b'a'; 10].into(), crate::typed::Format::Json.flags()).is_ok());
+        let compressed = Compressed::new((), Compression::Zlib);
+        assert!(compressed.compress(vec![b'a'; 10].into(), flags::PYLIBMC_ZLIB).is_ok());
+    }
+}
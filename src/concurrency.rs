@@ -0,0 +1,86 @@
+use tokio::sync::Semaphore;
+use std::io;
+use std::sync::Arc;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::layer::Layer;
+use crate::service::{Service, BoxFuture};
+
+// Holds back requests while `max` are already in flight, sending each once
+// an earlier one has been answered.  Services made by the same
+// `ConcurrencyLimitLayer` share one limit.
+pub struct ConcurrencyLimit<S> {
+    inner: Arc<S>,
+    permits: Arc<Semaphore>,
+}
+
+impl<S> ConcurrencyLimit<S> {
+    pub fn new(inner: S, max: usize) -> ConcurrencyLimit<S> {
+        ConcurrencyLimit::shared(inner, Arc::new(Semaphore::new(max.max(1))))
+    }
+
+    fn shared(inner: S, permits: Arc<Semaphore>) -> ConcurrencyLimit<S> {
+        ConcurrencyLimit{inner: Arc::new(inner), permits: permits}
+    }
+}
+
+impl<S> Service for ConcurrencyLimit<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+          S::Future: Send {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let inner = self.inner.clone();
+        let permits = self.permits.clone();
+        Box::pin(async move {
+            let _permit = permits.acquire_owned().await.expect("semaphore closed");
+            inner.call(req).await
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimitLayer {
+    permits: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimitLayer {
+    pub fn new(max: usize) -> ConcurrencyLimitLayer {
+        ConcurrencyLimitLayer{permits: Arc::new(Semaphore::new(max.max(1)))}
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimit<S>;
+
+    fn layer(&self, inner: S) -> ConcurrencyLimit<S> {
+        ConcurrencyLimit::shared(inner, self.permits.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::time::{Duration, Instant};
+    use crate::api::Api;
+    use crate::concurrency::ConcurrencyLimit;
+    use crate::mock::{Expectation, MockService};
+    use crate::response::Response;
+
+    #[tokio::test]
+    async fn limit() {
+        let mock = MockService::new();
+        for _ in 0..4 {
+            mock.expect(Expectation::any().respond(Response::Deleted).after(Duration::from_millis(50)));
+        }
+        let service = ConcurrencyLimit::new(mock, 2);
+        let start = Instant::now();
+        let results = future::join_all((0..4).map(|i| service.delete(i.to_string()))).await;
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,147 @@
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::str;
+
+use crate::api::Api;
+
+// A counter held as a memcached item, created on first use.  `incr` and `decr`
+// fail on a missing item, so a miss is followed by an `add` of the initial
+// value; if another client's `add` wins that race, the update is retried
+// against the item it created, so no increment is lost.
+//
+// The expiry is set when the item is created and is not extended by updates.
+pub struct Counter<A> {
+    api: Arc<A>,
+    key: String,
+    expiry: u32,
+}
+
+impl<A> Counter<A> {
+    pub fn new(api: A, key: String, expiry: u32) -> Counter<A> {
+        Counter::shared(Arc::new(api), key, expiry)
+    }
+
+    pub(crate) fn shared(api: Arc<A>, key: String, expiry: u32) -> Counter<A> {
+        Counter{api: api, key: key, expiry: expiry}
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl<A> Clone for Counter<A> {
+    fn clone(&self) -> Counter<A> {
+        Counter{api: self.api.clone(), key: self.key.clone(), expiry: self.expiry}
+    }
+}
+
+impl<A: Api<io::Error>> Counter<A> {
+    // Adds `delta`, returning the new value.
+    pub async fn incr(&self, delta: u64) -> io::Result<u64> {
+        self.update(delta, || self.api.incr(self.key.clone(), delta)).await
+    }
+
+    // Subtracts `delta`, returning the new value.  As with memcached's `decr`,
+    // the value stops at zero.
+    pub async fn decr(&self, delta: u64) -> io::Result<u64> {
+        self.update(0, || self.api.decr(self.key.clone(), delta)).await
+    }
+
+    // The current value, or zero if the counter doesn't exist.
+    pub async fn get(&self) -> io::Result<u64> {
+        match self.api.get(vec![self.key.clone()]).await?.into_iter().next() {
+            Some(value) => parse(&value.value),
+            None => Ok(0),
+        }
+    }
+
+    // Sets the counter to expire `expiry` seconds from now, returning whether
+    // it exists.
+    pub async fn touch(&self, expiry: u32) -> io::Result<bool> {
+        match self.api.touch(self.key.clone(), expiry).await {
+            Ok(()) => Ok(true),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Resets the counter by deleting it.
+    pub async fn reset(&self) -> io::Result<()> {
+        match self.api.delete(self.key.clone()).await {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    // Runs `update`, creating the counter at `initial` if it doesn't exist.
+    async fn update<F, U>(&self, initial: u64, update: F) -> io::Result<u64>
+        where F: Fn() -> U,
+              U: Future<Output = io::Result<u64>> {
+        match update().await {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                match self.api.add(self.key.clone(), initial.to_string().into(), 0, self.expiry).await {
+                    Ok(()) => Ok(initial),
+                    // Someone else created it first, so update theirs.
+                    Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => update().await,
+                    Err(err) => Err(err),
+                }
+            },
+            result => result,
+        }
+    }
+}
+
+fn parse(value: &[u8]) -> io::Result<u64> {
+    str::from_utf8(value).ok()
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid counter value"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Duration;
+    use crate::client::Client;
+    use crate::counter::Counter;
+    use crate::memory::InMemory;
+    use crate::server::{ApiService, Server};
+
+    // Goes through the text protocol both ways, so `incr` and `decr`
+    // answers are parsed from the wire.
+    async fn client() -> Client {
+        let addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(Server::new(|| Ok(ApiService::new(InMemory::new()))).serve(addr));
+        for _ in 0..50 {
+            if let Ok(client) = Client::connect(&addr).await {
+                return client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("server didn't start");
+    }
+
+    #[tokio::test]
+    async fn wire() {
+        let client = client().await;
+        let counter = Counter::new(client.clone(), String::from("hits"), 0);
+        assert_eq!(counter.get().await.unwrap(), 0);
+        assert_eq!(counter.incr(5).await.unwrap(), 5);
+        assert_eq!(counter.incr(u64::MAX - 5).await.unwrap(), u64::MAX);
+        assert_eq!(counter.incr(1).await.unwrap(), 0);
+        assert_eq!(counter.incr(10).await.unwrap(), 10);
+        assert_eq!(counter.decr(3).await.unwrap(), 7);
+        assert_eq!(counter.decr(100).await.unwrap(), 0);
+        assert_eq!(counter.get().await.unwrap(), 0);
+        assert!(counter.touch(60).await.unwrap());
+        counter.reset().await.unwrap();
+        counter.reset().await.unwrap();
+        assert!(!counter.touch(60).await.unwrap());
+
+        let other = Counter::new(client, String::from("misses"), 0);
+        assert_eq!(other.decr(1).await.unwrap(), 0);
+        assert_eq!(other.incr(2).await.unwrap(), 2);
+        assert_eq!(counter.get().await.unwrap(), 0);
+    }
+}
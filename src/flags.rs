@@ -0,0 +1,33 @@
+// Bit layout used for `Value.flags` by the typed and compression layers.
+// Where there is an existing convention it follows php-memcached, whose low
+// four bits give the type of the stored value.
+
+pub const TYPE_MASK: u16 = 0x000f;
+#[cfg(feature = "json")]
+pub const TYPE_JSON: u16 = 6;
+#[cfg(feature = "msgpack")]
+pub const TYPE_MSGPACK: u16 = 7;
+// No php-memcached equivalent.  Overlaps PYLIBMC_ZLIB, so bincode values
+// can't be stored under the pylibmc convention.
+#[cfg(feature = "bincode")]
+pub const TYPE_BINCODE: u16 = 15;
+
+// php-memcached marks compressed values with COMPRESSED plus one bit naming
+// the algorithm, and prefixes the compressed data with the original length.
+pub const COMPRESSED: u16 = 0x0010;
+pub const COMPRESSION_ZLIB: u16 = 0x0020;
+pub const COMPRESSION_FASTLZ: u16 = 0x0040;
+pub const COMPRESSION_ZSTD: u16 = 0x0080;
+// No php-memcached equivalent.
+pub const COMPRESSION_LZ4: u16 = 0x0100;
+pub const COMPRESSION_MASK: u16 = COMPRESSED | COMPRESSION_ZLIB | COMPRESSION_FASTLZ | COMPRESSION_ZSTD | COMPRESSION_LZ4;
+
+// pylibmc only knows zlib, and stores the bare zlib stream.  Its bit lies
+// within TYPE_MASK.
+pub const PYLIBMC_ZLIB: u16 = 0x0008;
+
+// Marks the manifest of a value split into chunks by `Chunked`.
+pub const CHUNKED: u16 = 0x8000;
+
+// Marks a value stored by `Loader` with its expiry time and load duration.
+pub const LOADER_ENVELOPE: u16 = 0x4000;
@@ -0,0 +1,151 @@
+use std::io;
+use std::sync::Arc;
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::api::Api;
+use crate::namespace::Namespaced;
+
+// A namespace whose keys embed a version number kept in memcached, so that
+// every key in it can be invalidated at once by bumping the version: keys
+// derived afterwards no longer match the old items, which are left to expire
+// or be evicted.
+//
+// A missing version counter is created from the current time rather than from
+// zero, so that a counter which has been evicted doesn't come back at a
+// version that has already been used.
+pub struct Generational<A> {
+    api: Arc<A>,
+    name: String,
+    expiry: u32,
+}
+
+impl<A> Generational<A> {
+    pub fn new<N: Into<String>>(api: A, name: N) -> Generational<A> {
+        Generational{api: Arc::new(api), name: name.into(), expiry: 0}
+    }
+
+    // Sets the expiry of the version counter, which defaults to never.
+    pub fn with_expiry(self, expiry: u32) -> Generational<A> {
+        Generational{expiry: expiry, ..self}
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn version_key(&self) -> String {
+        format!("ns:{}", self.name)
+    }
+}
+
+impl<A> Clone for Generational<A> {
+    fn clone(&self) -> Generational<A> {
+        Generational{api: self.api.clone(), name: self.name.clone(), expiry: self.expiry}
+    }
+}
+
+impl<A: Api<io::Error>> Generational<A> {
+    // The current version, creating the counter if there isn't one.
+    pub async fn version(&self) -> io::Result<u64> {
+        match self.read().await? {
+            Some(version) => Ok(version),
+            None => self.create().await,
+        }
+    }
+
+    // Invalidates every key in the namespace, returning the new version.
+    pub async fn invalidate(&self) -> io::Result<u64> {
+        match self.api.incr(self.version_key(), 1).await {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => self.create().await,
+            result => result,
+        }
+    }
+
+    // The prefix for keys in the current version of the namespace.
+    pub async fn prefix(&self) -> io::Result<String> {
+        Ok(format!("{}:{}:", self.name, self.version().await?))
+    }
+
+    // The key to use for `key` in the current version of the namespace.
+    pub async fn key(&self, key: &str) -> io::Result<String> {
+        Ok(self.prefix().await? + key)
+    }
+
+    // Wraps `service` so that its keys are in the current version of the
+    // namespace.  The version is read once, so the wrapper should be short
+    // lived, e.g. per incoming request.
+    pub async fn namespaced<S>(&self, service: S) -> io::Result<Namespaced<S>> {
+        Namespaced::new(service, self.prefix().await?)
+    }
+
+    async fn read(&self) -> io::Result<Option<u64>> {
+        match self.api.get(vec![self.version_key()]).await?.into_iter().next() {
+            Some(value) => parse(&value.value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Creates the counter, or reads the one created by whoever beat us to it.
+    async fn create(&self) -> io::Result<u64> {
+        let initial = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        match self.api.add(self.version_key(), initial.to_string().into(), 0, self.expiry).await {
+            Ok(()) => Ok(initial),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(self.read().await?.unwrap_or(initial)),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn parse(value: &[u8]) -> io::Result<u64> {
+    str::from_utf8(value).ok()
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid namespace version"))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+    use crate::api::Api;
+    use crate::generation::Generational;
+    use crate::memory::InMemory;
+    use crate::mock::{Expectation, MockService};
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::value::Value;
+
+    #[tokio::test]
+    async fn invalidate() {
+        let cache = InMemory::new();
+        let users = Generational::new(cache.clone(), "users");
+        let before = users.namespaced(cache.clone()).await.unwrap();
+        before.set(String::from("a"), Bytes::from_static(b"1"), 0, 0).await.unwrap();
+        assert_eq!(before.get(vec![String::from("a")]).await.unwrap().len(), 1);
+        let version = users.version().await.unwrap();
+        assert_eq!(users.invalidate().await.unwrap(), version + 1);
+        assert_eq!(users.key("a").await.unwrap(), format!("users:{}:a", version + 1));
+        let after = users.namespaced(cache.clone()).await.unwrap();
+        assert!(after.get(vec![String::from("a")]).await.unwrap().is_empty());
+        assert_eq!(before.get(vec![String::from("a")]).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn create() {
+        let cache = InMemory::new();
+        let users = Generational::new(cache.clone(), "users");
+        let versions = future::join_all((0..4).map(|_| users.version())).await;
+        assert!(versions.iter().all(|version| version.as_ref().unwrap() == versions[0].as_ref().unwrap()));
+        assert_eq!(cache.len(), 1);
+
+        // Another client creates the counter between our read and our add.
+        let mock = MockService::new();
+        let key = String::from("ns:users");
+        mock.expect(Expectation::new(Request::Get{keys: vec![key.clone()]}).respond(Response::Values(Vec::new())))
+            .expect(Expectation::any().respond(Response::NotStored))
+            .expect(Expectation::new(Request::Get{keys: vec![key.clone()]}).respond(Response::Values(vec![Value{key: key, value: Bytes::from_static(b"42"), flags: 0, cas: None}])));
+        assert_eq!(Generational::new(mock.clone(), "users").version().await.unwrap(), 42);
+        mock.verify();
+        assert!(matches!(mock.calls()[1], Request::Add{..}));
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+
+use crate::parse_utils::is_key_char;
+
+pub const MAX_KEY_LEN: usize = 250;
+
+// A key that is safe to put on the wire: non-empty, at most 250 bytes, and
+// free of spaces and control characters.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key(String);
+
+impl Key {
+    pub fn new<S: Into<String>>(key: S) -> io::Result<Key> {
+        let key = key.into();
+        Key::validate(&key)?;
+        Ok(Key(key))
+    }
+
+    pub fn validate(key: &str) -> io::Result<()> {
+        if key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty key"));
+        }
+        if key.len() > MAX_KEY_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("key longer than {} bytes: {}...", MAX_KEY_LEN, &key[..key.char_indices().nth(32).map(|(i, _)| i).unwrap_or(key.len())])));
+        }
+        if !key.bytes().all(is_key_char) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("key contains invalid characters: {:?}", key)));
+        }
+        Ok(())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl Deref for Key {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Key {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Key> for String {
+    fn from(key: Key) -> String {
+        key.0
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::Key;
+
+    #[test]
+    fn validate() {
+        assert!(Key::new("key").is_ok());
+        assert!(Key::new("a:b/c-d_e").is_ok());
+        assert!(Key::new("").is_err());
+        assert!(Key::new("with space").is_err());
+        assert!(Key::new("with\r\nnewline").is_err());
+        assert!(Key::new("caf\u{e9}").is_err());
+        assert!(Key::new("k".repeat(250)).is_ok());
+        assert!(Key::new("k".repeat(251)).is_err());
+    }
+}
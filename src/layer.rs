@@ -0,0 +1,113 @@
+// Wraps a service in another, such as a timeout or a rate limit.  Layers are
+// applied the same way to a `Client` and to an `ApiService`, since both are
+// services over `Request` and `Response`.  A layer may be applied to many
+// services, e.g. one per connection on a server, and state such as a
+// concurrency limit or metrics is shared between them all.
+pub trait Layer<S> {
+    type Service;
+
+    fn layer(&self, inner: S) -> Self::Service;
+}
+
+// A layer that leaves a service as it is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl<S> Layer<S> for Identity {
+    type Service = S;
+
+    fn layer(&self, inner: S) -> S {
+        inner
+    }
+}
+
+// Two layers, `inner` applied first.
+#[derive(Debug, Clone)]
+pub struct Stack<I, O> {
+    inner: I,
+    outer: O,
+}
+
+impl<S, I, O> Layer<S> for Stack<I, O>
+    where I: Layer<S>,
+          O: Layer<I::Service> {
+    type Service = O::Service;
+
+    fn layer(&self, inner: S) -> O::Service {
+        self.outer.layer(self.inner.layer(inner))
+    }
+}
+
+// Stacks up layers to wrap services in.  The first layer added is the
+// outermost, so it sees each request first and each response last.
+#[derive(Debug, Clone)]
+pub struct ServiceBuilder<L> {
+    layer: L,
+}
+
+impl ServiceBuilder<Identity> {
+    pub fn new() -> ServiceBuilder<Identity> {
+        ServiceBuilder{layer: Identity}
+    }
+}
+
+impl Default for ServiceBuilder<Identity> {
+    fn default() -> ServiceBuilder<Identity> {
+        ServiceBuilder::new()
+    }
+}
+
+impl<L> ServiceBuilder<L> {
+    pub fn layer<T>(self, layer: T) -> ServiceBuilder<Stack<T, L>> {
+        ServiceBuilder{layer: Stack{inner: layer, outer: self.layer}}
+    }
+
+    pub fn service<S>(&self, service: S) -> L::Service
+        where L: Layer<S> {
+        self.layer.layer(service)
+    }
+}
+
+impl<S, L: Layer<S>> Layer<S> for ServiceBuilder<L> {
+    type Service = L::Service;
+
+    fn layer(&self, inner: S) -> L::Service {
+        self.layer.layer(inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::api::Api;
+    use crate::concurrency::ConcurrencyLimitLayer;
+    use crate::layer::ServiceBuilder;
+    use crate::logging::LoggingLayer;
+    use crate::metrics::{Metrics, MetricsLayer};
+    use crate::mock::{Expectation, MockService};
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::timeout::TimeoutLayer;
+
+    #[tokio::test]
+    async fn stack() {
+        let metrics = Arc::new(Metrics::new());
+        let mock = MockService::new();
+        mock.expect(Expectation::new(Request::Version).respond(Response::Version(String::from("1.6"))))
+            .expect(Expectation::any().respond(Response::Deleted).after(Duration::from_millis(200)));
+        let service = ServiceBuilder::new()
+            .layer(MetricsLayer::new(metrics.clone()))
+            .layer(LoggingLayer)
+            .layer(TimeoutLayer::new(Duration::from_millis(20)))
+            .layer(ConcurrencyLimitLayer::new(4))
+            .service(mock.clone());
+        assert_eq!(service.version().await.unwrap(), "1.6");
+        assert_eq!(service.delete(String::from("a")).await.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        let stats = metrics.snapshot();
+        assert_eq!((stats["version"].requests, stats["version"].errors), (1, 0));
+        assert_eq!((stats["delete"].requests, stats["delete"].errors), (1, 1));
+        mock.verify();
+    }
+}
@@ -1,23 +1,81 @@
 #[macro_use]
 extern crate nom;
-extern crate futures;
-extern crate tokio_core;
-extern crate tokio_proto;
-extern crate tokio_service;
 
 mod parse_utils;
 mod request;
 mod response;
 mod value;
+mod service;
 mod proto;
 mod api;
 mod client;
 mod server;
+mod backoff;
+mod reconnect;
+mod retry;
+mod hash;
+mod ring;
+mod replicated;
+mod flags;
+mod typed;
+mod compression;
+mod chunked;
+mod update;
+mod lock;
+mod loader;
+mod coalesce;
+mod near_cache;
+mod key;
+mod namespace;
+mod generation;
+mod counter;
+mod rate_limit;
+mod memory;
+mod mock;
+mod stream;
+mod binary;
+mod multiplex;
+mod layer;
+mod logging;
+mod metrics;
+mod timeout;
+mod concurrency;
 
 pub use request::Request;
 pub use response::Response;
 pub use value::Value;
-pub use proto::Proto;
+pub use service::{Service, NewService, BoxFuture};
+pub use proto::{Proto, ClientCodec, ServerCodec, ClientFrame, ValueFrame, Streaming, MAX_VALUE_LEN, MAX_LINE_LEN};
 pub use api::{Api, ApiHelper};
 pub use client::Client;
-pub use server::{ApiService, serve};
+pub use server::{ApiService, IntoResponse, StreamingApi, StreamingApiService, Reply, Server, serve};
+pub use backoff::Backoff;
+pub use reconnect::{ReconnectingClient, ConnectionState};
+pub use retry::{Retry, RetryPolicy};
+pub use ring::Ring;
+pub use replicated::Replicated;
+pub use typed::{Format, TypedCache};
+pub use compression::{Compressed, Compression, FlagConvention};
+pub use chunked::Chunked;
+pub use update::Updater;
+pub use lock::Lock;
+pub use loader::Loader;
+pub use coalesce::Coalescing;
+pub use near_cache::NearCache;
+pub use key::{Key, MAX_KEY_LEN};
+pub use namespace::{Namespaced, MAX_PREFIX_LEN};
+pub use generation::Generational;
+pub use counter::Counter;
+pub use rate_limit::{RateLimiter, Quota, RateLimit, RateLimitLayer};
+pub use memory::InMemory;
+pub use mock::{MockService, Expectation};
+pub use stream::{ValueStream, BodyStream, ValueStreams};
+pub use binary::{BinaryProto, BinaryCodec, Packet};
+pub use multiplex::MultiplexClient;
+pub use layer::{Layer, Identity, Stack, ServiceBuilder};
+pub use logging::{Logging, LoggingLayer};
+pub use metrics::{Metrics, CommandStats, MetricsService, MetricsLayer, LATENCY_BUCKETS};
+#[cfg(feature = "metrics-http")]
+pub use metrics::serve_metrics;
+pub use timeout::{Timeout, TimeoutLayer};
+pub use concurrency::{ConcurrencyLimit, ConcurrencyLimitLayer};
@@ -0,0 +1,354 @@
+use bytes::Bytes;
+use tokio::sync::oneshot;
+use std::cmp;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::value::Value;
+use crate::api::Api;
+use crate::backoff::Backoff;
+use crate::lock::Lock;
+use crate::flags;
+
+// Expiry times above this are absolute Unix times rather than relative.
+const MAX_RELATIVE_EXPIRY: u32 = 60 * 60 * 24 * 30;
+const ENVELOPE_LEN: usize = 12;
+
+type LoadResult = Result<Bytes, (io::ErrorKind, String)>;
+type Waiters = HashMap<String, Vec<oneshot::Sender<LoadResult>>>;
+
+// Removes its key from the in-flight map once the load finishes or is
+// abandoned, so later callers don't wait on a load that will never complete.
+// The key is removed exactly once: after `complete` a new load of the same
+// key may already be in flight, and dropping this mustn't end that one.
+struct InFlight {
+    waiters: Arc<Mutex<Waiters>>,
+    key: Option<String>,
+}
+
+impl InFlight {
+    fn complete(mut self, result: &io::Result<Bytes>) {
+        let key = self.key.take().expect("load completed twice");
+        let waiters = self.waiters.lock().unwrap().remove(&key).unwrap_or_default();
+        for tx in waiters {
+            let _ = tx.send(match *result {
+                Ok(ref value) => Ok(value.clone()),
+                Err(ref err) => Err((err.kind(), err.to_string())),
+            });
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.waiters.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct LockOptions {
+    expiry: u32,
+    max_wait: Duration,
+}
+
+// Cache-aside loading: `get_or_load` returns the cached value for a key, or
+// runs the loader and caches its result on a miss.  Concurrent misses for the
+// same key within the process share a single load.
+//
+// With `with_lock`, a miss also takes an `add`-based lock so that only one
+// process loads the value while others poll for it.  With
+// `with_early_refresh`, values are stored with their expiry time and how long
+// they took to load, and each hit may trigger a background reload shortly
+// before expiry, with a probability that rises as expiry approaches and is
+// scaled by `beta` (1.0 is a good default).
+pub struct Loader<A> {
+    api: Arc<A>,
+    waiters: Arc<Mutex<Waiters>>,
+    lock: Option<LockOptions>,
+    beta: Option<f64>,
+}
+
+impl<A> Loader<A> {
+    pub fn new(api: A) -> Loader<A> {
+        Loader{api: Arc::new(api), waiters: Arc::new(Mutex::new(HashMap::new())), lock: None, beta: None}
+    }
+
+    pub fn with_lock(self, expiry: u32, max_wait: Duration) -> Loader<A> {
+        Loader{lock: Some(LockOptions{expiry: expiry, max_wait: max_wait}), ..self}
+    }
+
+    pub fn with_early_refresh(self, beta: f64) -> Loader<A> {
+        Loader{beta: Some(beta), ..self}
+    }
+}
+
+impl<A> Clone for Loader<A> {
+    fn clone(&self) -> Loader<A> {
+        Loader{api: self.api.clone(), waiters: self.waiters.clone(), lock: self.lock, beta: self.beta}
+    }
+}
+
+impl<A> Loader<A>
+    where A: Api<io::Error> + Send + Sync + 'static {
+    pub async fn get_or_load<F>(&self, key: String, expiry: u32, load: F) -> io::Result<Bytes>
+        where F: Future<Output = io::Result<Vec<u8>>> + Send + 'static {
+        // A failed read is treated as a miss, so the caller still gets a value.
+        let value = self.api.get(vec![key.clone()]).await.ok().and_then(|values| values.into_iter().next());
+        match value {
+            Some(value) => {
+                let (value, refresh) = self.unwrap(value);
+                if refresh {
+                    let loader = self.clone();
+                    tokio::spawn(async move {
+                        let _ = loader.fill(key, expiry, load).await;
+                    });
+                }
+                Ok(value)
+            },
+            None => self.fill(key, expiry, load).await,
+        }
+    }
+
+    async fn fill<F>(&self, key: String, expiry: u32, load: F) -> io::Result<Bytes>
+        where F: Future<Output = io::Result<Vec<u8>>> + Send + 'static {
+        let rx = {
+            let mut waiters = self.waiters.lock().unwrap();
+            match waiters.get_mut(&key) {
+                Some(waiters) => {
+                    let (tx, rx) = oneshot::channel();
+                    waiters.push(tx);
+                    Some(rx)
+                },
+                None => {
+                    waiters.insert(key.clone(), Vec::new());
+                    None
+                },
+            }
+        };
+        if let Some(rx) = rx {
+            return match rx.await {
+                Ok(Ok(value)) => Ok(value),
+                Ok(Err((kind, message))) => Err(io::Error::new(kind, message)),
+                Err(_) => Err(io::Error::other("load abandoned")),
+            };
+        }
+        let in_flight = InFlight{waiters: self.waiters.clone(), key: Some(key.clone())};
+        let result = self.locked_load(key, expiry, load).await;
+        in_flight.complete(&result);
+        result
+    }
+
+    async fn locked_load<F>(&self, key: String, expiry: u32, load: F) -> io::Result<Bytes>
+        where F: Future<Output = io::Result<Vec<u8>>> + Send + 'static {
+        let options = match self.lock {
+            Some(options) => options,
+            None => return self.load(key, expiry, load).await,
+        };
+        let lock = Lock::shared(self.api.clone(), format!("{}:lock", key), options.expiry);
+        match lock.try_acquire().await {
+            Ok(false) => {
+                match self.wait_for(key.clone(), options.max_wait).await {
+                    Some(value) => Ok(value),
+                    None => self.load(key, expiry, load).await,
+                }
+            },
+            // If the lock can't be taken, loading anyway beats failing.
+            Ok(true) | Err(_) => {
+                let result = self.load(key, expiry, load).await;
+                let _ = lock.release().await;
+                result
+            },
+        }
+    }
+
+    async fn load<F>(&self, key: String, expiry: u32, load: F) -> io::Result<Bytes>
+        where F: Future<Output = io::Result<Vec<u8>>> + Send + 'static {
+        let start = Instant::now();
+        let value = Bytes::from(load.await?);
+        let elapsed = start.elapsed();
+        let (stored, flags) = match self.beta {
+            Some(_) => (Bytes::from(envelope(&value, expiry, elapsed)), flags::LOADER_ENVELOPE),
+            None => (value.clone(), 0),
+        };
+        // Failing to cache the value shouldn't fail the caller.
+        let _ = self.api.set(key, stored, flags, expiry).await;
+        Ok(value)
+    }
+
+    // Polls for another process to store the value, giving up after
+    // `max_wait`.  As in `get_or_load`, a failed read counts as a miss.
+    async fn wait_for(&self, key: String, max_wait: Duration) -> Option<Bytes> {
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(500));
+        let deadline = Instant::now() + max_wait;
+        let mut attempt = 0;
+        loop {
+            if let Some(value) = self.api.get(vec![key.clone()]).await.ok().and_then(|values| values.into_iter().next()) {
+                return Some(self.unwrap(value).0);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            tokio::time::sleep(cmp::min(backoff.delay(attempt), deadline - now)).await;
+            attempt += 1;
+        }
+    }
+
+    // Strips the early refresh envelope, if any, and decides whether this
+    // read should trigger a refresh.
+    fn unwrap(&self, value: Value) -> (Bytes, bool) {
+        if value.flags & flags::LOADER_ENVELOPE == 0 || value.value.len() < ENVELOPE_LEN {
+            return (value.value, false);
+        }
+        let expires_at = value.value[..8].iter().fold(0u64, |n, byte| (n << 8) | *byte as u64);
+        let delta = value.value[8..ENVELOPE_LEN].iter().fold(0u32, |n, byte| (n << 8) | *byte as u32);
+        let refresh = match self.beta {
+            Some(beta) if expires_at != 0 => {
+                // XFetch: refresh when now - delta * beta * ln(rand) >= expiry.
+                let delta = delta as f64 / 1000.0;
+                let r = 1.0 - rand::random::<f64>();
+                unix_time() - delta * beta * r.ln() >= expires_at as f64
+            },
+            _ => false,
+        };
+        (value.value.slice(ENVELOPE_LEN..), refresh)
+    }
+}
+
+fn unix_time() -> f64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() as f64 + now.subsec_nanos() as f64 / 1e9
+}
+
+fn envelope(value: &[u8], expiry: u32, elapsed: Duration) -> Vec<u8> {
+    let expires_at = match expiry {
+        0 => 0,
+        expiry if expiry > MAX_RELATIVE_EXPIRY => expiry as u64,
+        expiry => unix_time() as u64 + expiry as u64,
+    };
+    let delta = cmp::min(elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64, u32::MAX as u64) as u32;
+    let mut envelope = Vec::with_capacity(ENVELOPE_LEN + value.len());
+    for shift in (0..8).rev() {
+        envelope.push((expires_at >> (shift * 8)) as u8);
+    }
+    for shift in (0..4).rev() {
+        envelope.push((delta >> (shift * 8)) as u8);
+    }
+    envelope.extend_from_slice(value);
+    envelope
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use crate::api::Api;
+    use crate::flags;
+    use crate::loader::{envelope, Loader, ENVELOPE_LEN, MAX_RELATIVE_EXPIRY};
+    use crate::memory::InMemory;
+    use crate::mock::{Expectation, MockService};
+    use crate::request::Request;
+    use crate::response::Response;
+
+    #[tokio::test]
+    async fn coalesce() {
+        let cache = InMemory::new();
+        let loader = Loader::new(cache.clone());
+        let loads = Arc::new(AtomicUsize::new(0));
+        let results = future::join_all((0..4).map(|_| {
+            let loads = loads.clone();
+            loader.get_or_load(String::from("a"), 0, async move {
+                loads.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok(b"1".to_vec())
+            })
+        })).await;
+        assert!(results.iter().all(|result| result.as_ref().unwrap() == &b"1"[..]));
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(&cache.get(vec![String::from("a")]).await.unwrap()[0].value[..], b"1");
+        assert!(loader.waiters.lock().unwrap().is_empty());
+
+        let err = loader.get_or_load(String::from("b"), 0, async { Err(io::Error::new(io::ErrorKind::TimedOut, "slow")) }).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(loader.waiters.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn abandoned() {
+        let loader = Loader::new(InMemory::new());
+        let leader = tokio::spawn({
+            let loader = loader.clone();
+            async move {
+                loader.get_or_load(String::from("a"), 0, future::pending()).await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let follower = tokio::spawn({
+            let loader = loader.clone();
+            async move {
+                loader.get_or_load(String::from("a"), 0, async { Ok(b"1".to_vec()) }).await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        leader.abort();
+        assert!(follower.await.unwrap().is_err());
+        assert!(loader.waiters.lock().unwrap().is_empty());
+        let value = loader.get_or_load(String::from("a"), 0, async { Ok(b"2".to_vec()) }).await.unwrap();
+        assert_eq!(&value[..], b"2");
+    }
+
+    #[tokio::test]
+    async fn failed_wait() {
+        // Another process holds the lock and reads fail, which is treated as
+        // a miss rather than an error.
+        let mock = MockService::new().with_fallback(InMemory::new());
+        mock.expect(Expectation::any().fail(io::ErrorKind::ConnectionReset, "reset"))
+            .expect(Expectation::any().respond(Response::NotStored))
+            .expect(Expectation::any().fail(io::ErrorKind::ConnectionReset, "reset"));
+        let loader = Loader::new(mock.clone()).with_lock(5, Duration::ZERO);
+        let value = loader.get_or_load(String::from("a"), 0, async { Ok(b"1".to_vec()) }).await.unwrap();
+        assert_eq!(&value[..], b"1");
+        mock.verify();
+        assert!(matches!(mock.calls()[1], Request::Add{ref key, ..} if key == "a:lock"));
+        assert_eq!(&mock.get(vec![String::from("a")]).await.unwrap()[0].value[..], b"1");
+    }
+
+    #[tokio::test]
+    async fn early_refresh() {
+        let cache = InMemory::new();
+        let loader = Loader::new(cache.clone()).with_early_refresh(1.0);
+        let loads = Arc::new(AtomicUsize::new(0));
+        let load = |value: &'static [u8]| {
+            let loads = loads.clone();
+            async move {
+                loads.fetch_add(1, Ordering::SeqCst);
+                Ok(value.to_vec())
+            }
+        };
+
+        // Far from expiry and quick to load, so never refreshed.
+        let value = loader.get_or_load(String::from("a"), 3600, load(b"1")).await.unwrap();
+        assert_eq!(&value[..], b"1");
+        let stored = cache.get(vec![String::from("a")]).await.unwrap().remove(0);
+        assert_eq!((stored.flags, &stored.value[ENVELOPE_LEN..]), (flags::LOADER_ENVELOPE, &b"1"[..]));
+        assert_eq!(&loader.get_or_load(String::from("a"), 3600, load(b"2")).await.unwrap()[..], b"1");
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        // Past its recorded expiry, so the hit is served and reloaded behind.
+        let stale = envelope(b"old", MAX_RELATIVE_EXPIRY + 1, Duration::from_millis(500));
+        cache.set(String::from("a"), stale.into(), flags::LOADER_ENVELOPE, 0).await.unwrap();
+        assert_eq!(&loader.get_or_load(String::from("a"), 3600, load(b"new")).await.unwrap()[..], b"old");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(&loader.get_or_load(String::from("a"), 3600, load(b"newer")).await.unwrap()[..], b"new");
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,184 @@
+use bytes::Bytes;
+use rand::{self, Rng};
+use std::cmp;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::api::Api;
+use crate::backoff::Backoff;
+
+// A best-effort mutex held as a memcached item.  The lock is taken with `add`
+// under a random token, so only one holder can create it, and expires after
+// `expiry` seconds if the holder goes away.  Release and extension check the
+// token first, so a holder whose lease has lapsed won't disturb the next one.
+//
+// As with any lock built on an evicting cache, an item may be evicted before
+// it expires, so this should not be relied on for correctness.
+pub struct Lock<A> {
+    api: Arc<A>,
+    key: String,
+    token: String,
+    expiry: u32,
+    backoff: Backoff,
+}
+
+impl<A> Lock<A> {
+    pub fn new(api: A, key: String, expiry: u32) -> Lock<A> {
+        Lock::shared(Arc::new(api), key, expiry)
+    }
+
+    pub(crate) fn shared(api: Arc<A>, key: String, expiry: u32) -> Lock<A> {
+        Lock{
+            api: api,
+            key: key,
+            token: format!("{:016x}", rand::thread_rng().gen::<u64>()),
+            expiry: expiry,
+            backoff: Backoff::new(Duration::from_millis(10), Duration::from_secs(1)),
+        }
+    }
+
+    pub fn with_backoff(self, backoff: Backoff) -> Lock<A> {
+        Lock{backoff: backoff, ..self}
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+impl<A> Clone for Lock<A> {
+    fn clone(&self) -> Lock<A> {
+        Lock{
+            api: self.api.clone(),
+            key: self.key.clone(),
+            token: self.token.clone(),
+            expiry: self.expiry,
+            backoff: self.backoff.clone(),
+        }
+    }
+}
+
+impl<A: Api<io::Error>> Lock<A> {
+    pub async fn try_acquire(&self) -> io::Result<bool> {
+        match self.api.add(self.key.clone(), self.token.clone().into(), 0, self.expiry).await {
+            Ok(()) => Ok(true),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Polls until the lock is acquired, failing with `TimedOut` if that
+    // takes longer than `max_wait`.
+    pub async fn acquire(&self, max_wait: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + max_wait;
+        let mut attempt = 0;
+        while !self.try_acquire().await? {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("timed out waiting for lock {}", self.key)));
+            }
+            tokio::time::sleep(cmp::min(self.backoff.delay(attempt), deadline - now)).await;
+            attempt += 1;
+        }
+        Ok(())
+    }
+
+    // Releases the lock if we still hold it, returning whether we did.  The
+    // item is first overwritten with `cas`, so that it can't be mistaken for
+    // ours if it changed hands since we checked, then deleted.
+    pub async fn release(&self) -> io::Result<bool> {
+        let cas = match self.api.gets(vec![self.key.clone()]).await?.into_iter().next() {
+            Some(ref value) if value.value == self.token.as_bytes() => value.cas.unwrap_or(0),
+            _ => return Ok(false),
+        };
+        match self.api.cas(self.key.clone(), Bytes::new(), 0, self.expiry, cas).await {
+            Ok(()) => {},
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists || err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        }
+        match self.api.delete(self.key.clone()).await {
+            Ok(()) => Ok(true),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Extends the lease to `expiry` seconds from now if we still hold the
+    // lock, returning whether we did.  The token is written back with `cas`,
+    // so a lock that changed hands since we checked is left alone.
+    pub async fn extend(&self, expiry: u32) -> io::Result<bool> {
+        let cas = match self.api.gets(vec![self.key.clone()]).await?.into_iter().next() {
+            Some(ref value) if value.value == self.token.as_bytes() => value.cas.unwrap_or(0),
+            _ => return Ok(false),
+        };
+        match self.api.cas(self.key.clone(), self.token.clone().into(), 0, expiry, cas).await {
+            Ok(()) => Ok(true),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists || err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::io;
+    use std::time::Duration;
+    use crate::api::Api;
+    use crate::lock::Lock;
+    use crate::memory::InMemory;
+    use crate::mock::{Expectation, MockService};
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::value::Value;
+
+    // An absolute expiry time long past, so the item expires at once.
+    const EXPIRED: u32 = 60 * 60 * 24 * 30 + 1;
+
+    #[tokio::test]
+    async fn acquire_release() {
+        let cache = InMemory::new();
+        let first = Lock::new(cache.clone(), String::from("lock"), 60);
+        let second = Lock::new(cache.clone(), String::from("lock"), 60);
+        assert!(first.try_acquire().await.unwrap());
+        assert!(!second.try_acquire().await.unwrap());
+        assert_eq!(second.acquire(Duration::from_millis(20)).await.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(!second.release().await.unwrap());
+        assert!(!second.extend(60).await.unwrap());
+        assert!(first.extend(120).await.unwrap());
+        assert!(first.release().await.unwrap());
+        assert!(cache.is_empty());
+        second.acquire(Duration::from_millis(20)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired() {
+        let cache = InMemory::new();
+        let first = Lock::new(cache.clone(), String::from("lock"), EXPIRED);
+        let second = Lock::new(cache.clone(), String::from("lock"), 60);
+        assert!(first.try_acquire().await.unwrap());
+        assert!(second.try_acquire().await.unwrap());
+        assert!(!first.extend(60).await.unwrap());
+        assert!(!first.release().await.unwrap());
+        assert_eq!(&cache.get(vec![String::from("lock")]).await.unwrap()[0].value[..], second.token().as_bytes());
+    }
+
+    #[tokio::test]
+    async fn extend_race() {
+        // The lease lapses and is taken by another holder between the read
+        // and the write.
+        let mock = MockService::new();
+        let lock = Lock::new(mock.clone(), String::from("lock"), 60);
+        let value = Value{key: String::from("lock"), value: Bytes::from(lock.token().to_string()), flags: 0, cas: Some(7)};
+        mock.expect(Expectation::new(Request::Gets{keys: vec![String::from("lock")]}).respond(Response::Values(vec![value])))
+            .expect(Expectation::any().respond(Response::Exists));
+        assert!(!lock.extend(60).await.unwrap());
+        mock.verify();
+        assert!(matches!(mock.calls()[1], Request::Cas{cas: 7, expiry: 60, ..}));
+    }
+}
@@ -0,0 +1,64 @@
+use log::{debug, warn};
+use std::io;
+use std::time::Instant;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::layer::Layer;
+use crate::service::{Service, BoxFuture};
+
+// Logs each request with its outcome and how long it took, through the `log`
+// crate: answers at debug level, and errors and error responses as warnings.
+// Values and payloads are left out.
+pub struct Logging<S> {
+    inner: S,
+}
+
+impl<S> Logging<S> {
+    pub fn new(inner: S) -> Logging<S> {
+        Logging{inner: inner}
+    }
+}
+
+impl<S> Service for Logging<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error>,
+          S::Future: Send + 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let command = req.command();
+        let target = match req {
+            Request::Get{ref keys} | Request::Gets{ref keys} => format!("{} keys", keys.len()),
+            ref req => req.key().unwrap_or("").to_string(),
+        };
+        let start = Instant::now();
+        let rsp = self.inner.call(req);
+        Box::pin(async move {
+            let result = rsp.await;
+            let elapsed = start.elapsed();
+            match result {
+                Ok(Response::Values(ref values)) => debug!("{} {}: {} values in {:?}", command, target, values.len(), elapsed),
+                Ok(ref rsp @ Response::Error) |
+                Ok(ref rsp @ Response::ClientError(_)) |
+                Ok(ref rsp @ Response::ServerError(_)) => warn!("{} {}: {:?} in {:?}", command, target, rsp, elapsed),
+                Ok(ref rsp) => debug!("{} {}: {:?} in {:?}", command, target, rsp, elapsed),
+                Err(ref err) => warn!("{} {}: {} after {:?}", command, target, err, elapsed),
+            }
+            result
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoggingLayer;
+
+impl<S> Layer<S> for LoggingLayer {
+    type Service = Logging<S>;
+
+    fn layer(&self, inner: S) -> Logging<S> {
+        Logging::new(inner)
+    }
+}
@@ -0,0 +1,243 @@
+use bytes::Bytes;
+use futures::future::{self, Ready};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::str;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::value::Value;
+use crate::service::Service;
+
+// Expiry times above this are absolute Unix times rather than relative.
+const MAX_RELATIVE_EXPIRY: u32 = 60 * 60 * 24 * 30;
+
+struct Item {
+    value: Bytes,
+    flags: u16,
+    cas: u64,
+    expires: Option<Instant>,
+}
+
+struct Store {
+    items: HashMap<String, Item>,
+    next_cas: u64,
+}
+
+impl Store {
+    fn get(&mut self, key: &str) -> Option<&mut Item> {
+        let now = Instant::now();
+        if self.items.get(key).and_then(|item| item.expires).is_some_and(|expires| expires <= now) {
+            self.items.remove(key);
+        }
+        self.items.get_mut(key)
+    }
+
+    fn store(&mut self, key: String, value: Bytes, flags: u16, expiry: u32) {
+        self.next_cas += 1;
+        self.items.insert(key, Item{value: value, flags: flags, cas: self.next_cas, expires: expires(expiry)});
+    }
+
+    fn modify<F: FnOnce(&mut Vec<u8>)>(&mut self, key: &str, f: F) -> bool {
+        self.next_cas += 1;
+        let cas = self.next_cas;
+        match self.get(key) {
+            Some(item) => {
+                let mut value = item.value.to_vec();
+                f(&mut value);
+                item.value = value.into();
+                item.cas = cas;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn update(&mut self, key: &str, incr: bool, delta: u64) -> Response {
+        self.next_cas += 1;
+        let cas = self.next_cas;
+        let item = match self.get(key) {
+            Some(item) => item,
+            None => return Response::NotFound,
+        };
+        let value = match str::from_utf8(&item.value).ok().and_then(|value| value.parse::<u64>().ok()) {
+            Some(value) => value,
+            None => return Response::ClientError(String::from("cannot increment or decrement non-numeric value")),
+        };
+        let value = if incr { value.wrapping_add(delta) } else { value.saturating_sub(delta) };
+        item.value = value.to_string().into();
+        item.cas = cas;
+        Response::UpdatedValue(value)
+    }
+
+    fn values(&mut self, keys: Vec<String>, cas: bool) -> Response {
+        let mut values = Vec::new();
+        for key in keys {
+            if let Some(item) = self.get(&key) {
+                values.push(Value{value: item.value.clone(), flags: item.flags, cas: if cas { Some(item.cas) } else { None }, key: key});
+            }
+        }
+        Response::Values(values)
+    }
+
+    fn call(&mut self, req: Request) -> Response {
+        match req {
+            Request::Set{key, value, flags, expiry, ..} => {
+                self.store(key, value, flags, expiry);
+                Response::Stored
+            },
+            Request::Add{key, value, flags, expiry, ..} => {
+                if self.get(&key).is_some() {
+                    return Response::NotStored;
+                }
+                self.store(key, value, flags, expiry);
+                Response::Stored
+            },
+            Request::Replace{key, value, flags, expiry, ..} => {
+                if self.get(&key).is_none() {
+                    return Response::NotStored;
+                }
+                self.store(key, value, flags, expiry);
+                Response::Stored
+            },
+            Request::Append{key, value, ..} => {
+                if self.modify(&key, |item| item.extend_from_slice(&value)) { Response::Stored } else { Response::NotStored }
+            },
+            Request::Prepend{key, value, ..} => {
+                if self.modify(&key, |item| { item.splice(0..0, value); }) { Response::Stored } else { Response::NotStored }
+            },
+            Request::Cas{key, value, flags, expiry, cas, ..} => {
+                match self.get(&key).map(|item| item.cas) {
+                    None => Response::NotFound,
+                    Some(current) if current != cas => Response::Exists,
+                    Some(_) => {
+                        self.store(key, value, flags, expiry);
+                        Response::Stored
+                    },
+                }
+            },
+            Request::Get{keys} => self.values(keys, false),
+            Request::Gets{keys} => self.values(keys, true),
+            Request::Delete{key, ..} => {
+                if self.get(&key).is_none() {
+                    return Response::NotFound;
+                }
+                self.items.remove(&key);
+                Response::Deleted
+            },
+            Request::Incr{key, value, ..} => self.update(&key, true, value),
+            Request::Decr{key, value, ..} => self.update(&key, false, value),
+            Request::Touch{key, expiry, ..} => {
+                match self.get(&key) {
+                    Some(item) => {
+                        item.expires = expires(expiry);
+                        Response::Touched
+                    },
+                    None => Response::NotFound,
+                }
+            },
+            Request::FlushAll{delay, ..} => {
+                match delay {
+                    Some(delay) if delay > 0 => {
+                        let expires = expires(delay);
+                        for item in self.items.values_mut() {
+                            item.expires = match item.expires {
+                                Some(current) if Some(current) < expires => Some(current),
+                                _ => expires,
+                            };
+                        }
+                    },
+                    _ => self.items.clear(),
+                }
+                Response::Ok
+            },
+            Request::Version => Response::Version(String::from("in-memory")),
+        }
+    }
+}
+
+fn expires(expiry: u32) -> Option<Instant> {
+    match expiry {
+        0 => None,
+        expiry if expiry > MAX_RELATIVE_EXPIRY => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            Some(Instant::now() + Duration::from_secs(expiry as u64).checked_sub(now).unwrap_or_default())
+        },
+        expiry => Some(Instant::now() + Duration::from_secs(expiry as u64)),
+    }
+}
+
+// A memcached stand-in holding its items in memory, for testing code that
+// talks to a cache without a server.  It follows memcached's semantics for
+// every command, including expiry and CAS values, but never evicts.  Clones
+// share the same items.
+#[derive(Clone)]
+pub struct InMemory {
+    store: Arc<Mutex<Store>>,
+}
+
+impl InMemory {
+    pub fn new() -> InMemory {
+        InMemory{store: Arc::new(Mutex::new(Store{items: HashMap::new(), next_cas: 0}))}
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for InMemory {
+    fn default() -> InMemory {
+        InMemory::new()
+    }
+}
+
+impl Service for InMemory {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = Ready<io::Result<Response>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        if let Err(err) = req.validate() {
+            return future::err(err);
+        }
+        future::ok(self.store.lock().unwrap().call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use std::io;
+    use crate::api::Api;
+    use crate::memory::InMemory;
+
+    #[test]
+    fn commands() {
+        let cache = InMemory::new();
+        block_on(cache.set(String::from("a"), Bytes::from_static(b"1"), 3, 0)).unwrap();
+        assert_eq!(block_on(cache.add(String::from("a"), Bytes::from_static(b"2"), 0, 0)).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(block_on(cache.incr(String::from("a"), 41)).unwrap(), 42);
+        assert_eq!(block_on(cache.decr(String::from("a"), 50)).unwrap(), 0);
+        block_on(cache.append(String::from("a"), Bytes::from_static(b"1"))).unwrap();
+        block_on(cache.prepend(String::from("a"), Bytes::from_static(b"2"))).unwrap();
+        let values = block_on(cache.gets(vec![String::from("a"), String::from("b")])).unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(&values[0].value[..], b"201");
+        assert_eq!(values[0].flags, 3);
+        let cas = values[0].cas.unwrap();
+        block_on(cache.cas(String::from("a"), Bytes::from_static(b"x"), 0, 0, cas)).unwrap();
+        assert_eq!(block_on(cache.cas(String::from("a"), Bytes::from_static(b"y"), 0, 0, cas)).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        block_on(cache.delete(String::from("a"))).unwrap();
+        assert_eq!(block_on(cache.delete(String::from("a"))).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,388 @@
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::layer::Layer;
+use crate::service::{Service, BoxFuture};
+
+// The upper bounds, in seconds, of the latency histogram's buckets.  A last
+// bucket catches everything slower.
+pub const LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+// Counts for one command.  Errors include error responses such as
+// `SERVER_ERROR` as well as failed requests.  Hits and misses are counted per
+// key, for `get` and `gets` only.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CommandStats {
+    pub requests: u64,
+    pub errors: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub total_time: Duration,
+    // How many requests took up to each of `LATENCY_BUCKETS`, and longer.
+    // Each request is only counted in the first bucket it fits.
+    pub latency: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl CommandStats {
+    // The share of keys read that were found, if any were read.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        match self.hits + self.misses {
+            0 => None,
+            reads => Some(self.hits as f64 / reads as f64),
+        }
+    }
+}
+
+// Per-command counts gathered by `MetricsLayer`, and byte and connection
+// counts gathered by a `Client` or `Server` given these metrics.  Shared
+// between every service and connection using it.
+#[derive(Debug)]
+pub struct Metrics {
+    prefix: String,
+    commands: Mutex<BTreeMap<&'static str, CommandStats>>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    connections: AtomicI64,
+    connections_opened: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::with_prefix("memcache")
+    }
+
+    // Names every metric `<prefix>_...`, to tell apart e.g. a client's and a
+    // server's metrics in the same process.
+    pub fn with_prefix<P: Into<String>>(prefix: P) -> Metrics {
+        Metrics{
+            prefix: prefix.into(),
+            commands: Mutex::new(BTreeMap::new()),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            connections: AtomicI64::new(0),
+            connections_opened: AtomicU64::new(0),
+        }
+    }
+
+    // The counts so far, keyed by command name.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, CommandStats> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    // How many connections are open now.
+    pub fn connections(&self) -> i64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, command: &'static str, error: bool, found: Option<(u64, u64)>, elapsed: Duration) {
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(command).or_default();
+        stats.requests += 1;
+        stats.errors += error as u64;
+        if let Some((hits, misses)) = found {
+            stats.hits += hits;
+            stats.misses += misses;
+        }
+        stats.total_time += elapsed;
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(LATENCY_BUCKETS.len());
+        stats.latency[bucket] += 1;
+    }
+
+    // Writes the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let commands = self.snapshot();
+        let prefix = &self.prefix;
+        let mut out = String::new();
+        let family = |out: &mut String, name: &str, kind: &str, help: &str| {
+            let _ = writeln!(out, "# HELP {}_{} {}", prefix, name, help);
+            let _ = writeln!(out, "# TYPE {}_{} {}", prefix, name, kind);
+        };
+        family(&mut out, "requests_total", "counter", "Requests by command.");
+        for (command, stats) in commands.iter() {
+            let _ = writeln!(out, "{}_requests_total{{command=\"{}\"}} {}", prefix, command, stats.requests);
+        }
+        family(&mut out, "errors_total", "counter", "Failed requests and error responses by command.");
+        for (command, stats) in commands.iter() {
+            let _ = writeln!(out, "{}_errors_total{{command=\"{}\"}} {}", prefix, command, stats.errors);
+        }
+        family(&mut out, "hits_total", "counter", "Keys found by get and gets.");
+        for (command, stats) in commands.iter().filter(|&(command, _)| *command == "get" || *command == "gets") {
+            let _ = writeln!(out, "{}_hits_total{{command=\"{}\"}} {}", prefix, command, stats.hits);
+        }
+        family(&mut out, "misses_total", "counter", "Keys not found by get and gets.");
+        for (command, stats) in commands.iter().filter(|&(command, _)| *command == "get" || *command == "gets") {
+            let _ = writeln!(out, "{}_misses_total{{command=\"{}\"}} {}", prefix, command, stats.misses);
+        }
+        family(&mut out, "request_duration_seconds", "histogram", "Request latency by command.");
+        for (command, stats) in commands.iter() {
+            let mut count = 0;
+            for (bound, n) in LATENCY_BUCKETS.iter().zip(stats.latency.iter()) {
+                count += n;
+                let _ = writeln!(out, "{}_request_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}", prefix, command, bound, count);
+            }
+            let _ = writeln!(out, "{}_request_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}", prefix, command, stats.requests);
+            let _ = writeln!(out, "{}_request_duration_seconds_sum{{command=\"{}\"}} {}", prefix, command, stats.total_time.as_secs_f64());
+            let _ = writeln!(out, "{}_request_duration_seconds_count{{command=\"{}\"}} {}", prefix, command, stats.requests);
+        }
+        family(&mut out, "bytes_read_total", "counter", "Bytes read from connections.");
+        let _ = writeln!(out, "{}_bytes_read_total {}", prefix, self.bytes_read());
+        family(&mut out, "bytes_written_total", "counter", "Bytes written to connections.");
+        let _ = writeln!(out, "{}_bytes_written_total {}", prefix, self.bytes_written());
+        family(&mut out, "connections", "gauge", "Open connections.");
+        let _ = writeln!(out, "{}_connections {}", prefix, self.connections());
+        family(&mut out, "connections_total", "counter", "Connections opened.");
+        let _ = writeln!(out, "{}_connections_total {}", prefix, self.connections_opened.load(Ordering::Relaxed));
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+fn is_error(result: &io::Result<Response>) -> bool {
+    matches!(*result, Err(_) | Ok(Response::Error) | Ok(Response::ClientError(_)) | Ok(Response::ServerError(_)))
+}
+
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> MetricsService<S> {
+    pub fn new(inner: S, metrics: Arc<Metrics>) -> MetricsService<S> {
+        MetricsService{inner: inner, metrics: metrics}
+    }
+}
+
+impl<S> Service for MetricsService<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error>,
+          S::Future: Send + 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let command = req.command();
+        let reads = match req {
+            Request::Get{ref keys} | Request::Gets{ref keys} => Some(keys.len() as u64),
+            _ => None,
+        };
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        let rsp = self.inner.call(req);
+        Box::pin(async move {
+            let result = rsp.await;
+            let found = match (reads, &result) {
+                (Some(reads), Ok(Response::Values(values))) => {
+                    let hits = values.len() as u64;
+                    Some((hits, reads.saturating_sub(hits)))
+                },
+                _ => None,
+            };
+            metrics.record(command, is_error(&result), found, start.elapsed());
+            result
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> MetricsLayer {
+        MetricsLayer{metrics: metrics}
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> MetricsService<S> {
+        MetricsService::new(inner, self.metrics.clone())
+    }
+}
+
+// A connection that counts the bytes through it, and counts itself as open
+// until dropped.
+pub(crate) struct Counted<T> {
+    io: T,
+    metrics: Arc<Metrics>,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(io: T, metrics: Arc<Metrics>) -> Counted<T> {
+        metrics.connections.fetch_add(1, Ordering::Relaxed);
+        metrics.connections_opened.fetch_add(1, Ordering::Relaxed);
+        Counted{io: io, metrics: metrics}
+    }
+}
+
+impl<T> Drop for Counted<T> {
+    fn drop(&mut self) {
+        self.metrics.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.io).poll_read(cx, buf);
+        this.metrics.bytes_read.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.metrics.bytes_written.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+// Serves `metrics` to Prometheus over HTTP at `/metrics` on `addr`.  Only
+// enough HTTP is spoken for a scrape: each connection gets one response and
+// is closed.  Only fails if `addr` can't be bound; failed accepts are logged.
+#[cfg(feature = "metrics-http")]
+pub async fn serve_metrics(addr: std::net::SocketAddr, metrics: Vec<Arc<Metrics>>) -> io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log::warn!("accept on {}: {}", addr, err);
+                tokio::time::sleep(crate::server::ACCEPT_BACKOFF).await;
+                continue;
+            },
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // Read up to the end of the request's headers, within reason.
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let response = if request.starts_with(b"GET /metrics ") || request.starts_with(b"GET /metrics?") {
+                let body: String = metrics.iter().map(|metrics| metrics.render()).collect();
+                format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+            } else {
+                String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::api::Api;
+    use crate::client::Client;
+    use crate::memory::InMemory;
+    use crate::metrics::{Metrics, MetricsService};
+    use crate::server::{ApiService, Server};
+
+    #[tokio::test]
+    async fn exposition() {
+        let metrics = Arc::new(Metrics::with_prefix("cache"));
+        let service = MetricsService::new(InMemory::new(), metrics.clone());
+        service.set(String::from("a"), Bytes::from_static(b"1"), 0, 0).await.unwrap();
+        service.get(vec![String::from("a"), String::from("b"), String::from("c")]).await.unwrap();
+        assert!(service.incr(String::from("b"), 1).await.is_err());
+        let stats = metrics.snapshot();
+        assert_eq!((stats["get"].hits, stats["get"].misses), (1, 2));
+        // NOT_FOUND is an answer, not an error.
+        assert_eq!((stats["incr"].requests, stats["incr"].errors), (1, 0));
+        let text = metrics.render();
+        assert!(text.contains("# TYPE cache_requests_total counter\n"));
+        assert!(text.contains("cache_requests_total{command=\"set\"} 1\n"));
+        assert!(text.contains("cache_misses_total{command=\"get\"} 2\n"));
+        assert!(text.contains("cache_request_duration_seconds_bucket{command=\"get\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("cache_connections 0\n"));
+    }
+
+    #[tokio::test]
+    async fn connections() {
+        let addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = Arc::new(Metrics::with_prefix("server"));
+        tokio::spawn(Server::new(|| Ok(ApiService::new(InMemory::new()))).with_metrics(server.clone()).serve(addr));
+        let client = Arc::new(Metrics::with_prefix("client"));
+        let mut attempts = 0;
+        let cache = loop {
+            match Client::connect_with_metrics(&addr, client.clone()).await {
+                Ok(cache) => break cache,
+                Err(_) if attempts < 50 => tokio::time::sleep(Duration::from_millis(10)).await,
+                Err(err) => panic!("{}", err),
+            }
+            attempts += 1;
+        };
+        cache.set(String::from("a"), Bytes::from_static(b"value"), 0, 0).await.unwrap();
+        cache.version().await.unwrap();
+        assert_eq!(client.bytes_written(), server.bytes_read());
+        assert_eq!(client.bytes_read(), server.bytes_written());
+        assert!(client.bytes_written() > 5);
+        assert_eq!((client.connections(), server.connections()), (1, 1));
+
+        #[cfg(feature = "metrics-http")]
+        {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let http: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            tokio::spawn(crate::metrics::serve_metrics(http, vec![client.clone(), server.clone()]));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut stream = tokio::net::TcpStream::connect(http).await.unwrap();
+            stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("client_connections 1\n"));
+            assert!(response.contains("server_connections_total 1\n"));
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use futures::{FutureExt, future};
+use tokio::sync::oneshot;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::service::{Service, BoxFuture};
+
+type Fallback = Box<dyn Fn(Request) -> BoxFuture<Response> + Send + Sync>;
+
+// A scripted answer to a request: the request to expect (or any request), and
+// the response or error to give back, optionally after a delay.
+pub struct Expectation {
+    request: Option<Request>,
+    result: Result<Response, (io::ErrorKind, String)>,
+    delay: Option<Duration>,
+}
+
+impl Expectation {
+    pub fn new(request: Request) -> Expectation {
+        Expectation{request: Some(request), result: Err((io::ErrorKind::Other, String::from("no response scripted"))), delay: None}
+    }
+
+    // Matches whatever request comes next.
+    pub fn any() -> Expectation {
+        Expectation{request: None, ..Expectation::new(Request::Version)}
+    }
+
+    pub fn respond(self, response: Response) -> Expectation {
+        Expectation{result: Ok(response), ..self}
+    }
+
+    pub fn fail<M: Into<String>>(self, kind: io::ErrorKind, message: M) -> Expectation {
+        Expectation{result: Err((kind, message.into())), ..self}
+    }
+
+    pub fn after(self, delay: Duration) -> Expectation {
+        Expectation{delay: Some(delay), ..self}
+    }
+
+    fn matches(&self, req: &Request) -> bool {
+        self.request.as_ref().is_none_or(|request| request == req)
+    }
+}
+
+struct State {
+    expectations: VecDeque<Expectation>,
+    calls: Vec<Request>,
+    fallback: Option<Fallback>,
+}
+
+// A service answering requests from a script of expectations, for testing
+// code that talks to a cache, through the `Api` it gets for free.  Each
+// request is recorded and answered by the first unused expectation that
+// matches it.  Requests that match none go to the fallback service if there
+// is one, e.g. an `InMemory`, and fail with `InvalidInput` otherwise.  Clones
+// share the same script and record.
+#[derive(Clone)]
+pub struct MockService {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockService {
+    pub fn new() -> MockService {
+        MockService{state: Arc::new(Mutex::new(State{expectations: VecDeque::new(), calls: Vec::new(), fallback: None}))}
+    }
+
+    pub fn with_fallback<S>(self, service: S) -> MockService
+        where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+              S::Future: Send + 'static {
+        self.state.lock().unwrap().fallback = Some(Box::new(move |req| Box::pin(service.call(req))));
+        self
+    }
+
+    pub fn expect(&self, expectation: Expectation) -> &MockService {
+        self.state.lock().unwrap().expectations.push_back(expectation);
+        self
+    }
+
+    // Every request received so far, in order.
+    pub fn calls(&self) -> Vec<Request> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    // Panics if any expectation hasn't been used.
+    pub fn verify(&self) {
+        let state = self.state.lock().unwrap();
+        if !state.expectations.is_empty() {
+            let pending: Vec<_> = state.expectations.iter().map(|expectation| &expectation.request).collect();
+            panic!("unmet expectations: {:?}", pending);
+        }
+    }
+}
+
+impl Default for MockService {
+    fn default() -> MockService {
+        MockService::new()
+    }
+}
+
+impl Service for MockService {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(req.clone());
+        let expectation = match state.expectations.iter().position(|expectation| expectation.matches(&req)) {
+            Some(index) => state.expectations.remove(index),
+            None => None,
+        };
+        let expectation = match expectation {
+            Some(expectation) => expectation,
+            None => {
+                return match state.fallback {
+                    Some(ref fallback) => fallback(req),
+                    None => Box::pin(future::err(io::Error::new(io::ErrorKind::InvalidInput, format!("unexpected request: {:?}", req)))),
+                };
+            },
+        };
+        let result = expectation.result.map_err(|(kind, message)| io::Error::new(kind, message));
+        match expectation.delay {
+            Some(delay) => {
+                // Slept on a thread, so no particular runtime is needed to
+                // drive it.
+                let (tx, rx) = oneshot::channel();
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    let _ = tx.send(());
+                });
+                Box::pin(rx.map(move |_| result))
+            },
+            None => Box::pin(future::ready(result)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use std::io;
+    use std::time::Duration;
+    use crate::api::Api;
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::mock::{Expectation, MockService};
+    use crate::memory::InMemory;
+
+    #[test]
+    fn script() {
+        let mock = MockService::new().with_fallback(InMemory::new());
+        mock.expect(Expectation::new(Request::Version).respond(Response::Version(String::from("1.6"))).after(Duration::from_millis(1)))
+            .expect(Expectation::any().fail(io::ErrorKind::BrokenPipe, "gone"));
+        assert_eq!(block_on(mock.version()).unwrap(), "1.6");
+        assert_eq!(block_on(mock.delete(String::from("a"))).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        block_on(mock.set(String::from("a"), Bytes::from_static(b"1"), 0, 0)).unwrap();
+        mock.verify();
+        assert_eq!(mock.calls().len(), 3);
+        assert_eq!(mock.calls()[1], Request::Delete{key: String::from("a"), noreply: false});
+    }
+}
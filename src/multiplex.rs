@@ -0,0 +1,238 @@
+use futures::FutureExt;
+use futures::future;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::Framed;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::value::Value;
+use crate::service::{Service, BoxFuture};
+use crate::binary::{BinaryProto, BinaryCodec};
+
+// A request awaiting its response, and the values of a multi-get so far.
+struct Pending {
+    tx: oneshot::Sender<io::Result<Response>>,
+    values: Vec<Value>,
+    // The first failed key of a multi-get, which answers it in place of the
+    // values.
+    error: Option<Response>,
+    cas: bool,
+}
+
+// A multiplexed connection to a memcached server over the binary protocol.
+// Each request is tagged with its own opaque and answered whenever its
+// response arrives, so a slow request doesn't hold up the others even if the
+// server answers out of order.  As with `Client`, the connection is driven by
+// a task spawned on the current runtime, which exits once every clone of the
+// client has been dropped.
+#[derive(Clone)]
+pub struct MultiplexClient {
+    requests: mpsc::UnboundedSender<(Request, oneshot::Sender<io::Result<Response>>)>,
+}
+
+impl MultiplexClient {
+    pub async fn connect(addr: &SocketAddr) -> io::Result<MultiplexClient> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(BinaryProto.client(stream), rx));
+        Ok(MultiplexClient{requests: tx})
+    }
+}
+
+fn broken_pipe() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "connection closed")
+}
+
+async fn run(mut transport: Framed<TcpStream, BinaryCodec>, mut requests: mpsc::UnboundedReceiver<(Request, oneshot::Sender<io::Result<Response>>)>) {
+    let mut pending: HashMap<u32, Pending> = HashMap::new();
+    let mut next_opaque: u32 = 0;
+    let mut open = true;
+    let failure = loop {
+        tokio::select! {
+            req = requests.recv(), if open => {
+                let (req, tx) = match req {
+                    Some(req) => req,
+                    None => {
+                        open = false;
+                        if pending.is_empty() {
+                            break None;
+                        }
+                        continue;
+                    },
+                };
+                // Write every request that is already queued before flushing.
+                let mut next = Some((req, tx));
+                while let Some((req, tx)) = next.take() {
+                    while pending.contains_key(&next_opaque) {
+                        next_opaque = next_opaque.wrapping_add(1);
+                    }
+                    let opaque = next_opaque;
+                    next_opaque = next_opaque.wrapping_add(1);
+                    let cas = matches!(req, Request::Gets{..});
+                    if let Err(err) = transport.feed((opaque, req)).await {
+                        let _ = tx.send(Err(err));
+                        break;
+                    }
+                    pending.insert(opaque, Pending{tx: tx, values: Vec::new(), error: None, cas: cas});
+                    next = requests.try_recv().ok();
+                }
+                if let Err(err) = transport.flush().await {
+                    break Some(err);
+                }
+            },
+            packet = transport.next(), if !pending.is_empty() => {
+                let packet = match packet {
+                    Some(Ok(packet)) => packet,
+                    Some(Err(err)) => break Some(err),
+                    None => break None,
+                };
+                let entry = match pending.get_mut(&packet.opaque) {
+                    Some(entry) => entry,
+                    None => break Some(io::Error::new(io::ErrorKind::InvalidData, format!("response to unknown opaque {}", packet.opaque))),
+                };
+                if packet.is_value() {
+                    match packet.to_value() {
+                        Ok(mut value) => {
+                            if !entry.cas {
+                                value.cas = None;
+                            }
+                            entry.values.push(value);
+                        },
+                        Err(err) => break Some(err),
+                    }
+                    continue;
+                }
+                if packet.is_value_error() {
+                    match packet.to_response() {
+                        Ok(rsp) => {
+                            entry.error.get_or_insert(rsp);
+                        },
+                        Err(err) => break Some(err),
+                    }
+                    continue;
+                }
+                let rsp = match packet.to_response() {
+                    Ok(Response::Values(_)) => match entry.error.take() {
+                        Some(rsp) => rsp,
+                        None => Response::Values(std::mem::take(&mut entry.values)),
+                    },
+                    Ok(rsp) => rsp,
+                    Err(err) => break Some(err),
+                };
+                if let Some(entry) = pending.remove(&packet.opaque) {
+                    let _ = entry.tx.send(Ok(rsp));
+                }
+                if !open && pending.is_empty() {
+                    break None;
+                }
+            },
+        }
+    };
+    for (_, entry) in pending {
+        let err = match failure {
+            Some(ref err) => io::Error::new(err.kind(), err.to_string()),
+            None => broken_pipe(),
+        };
+        let _ = entry.tx.send(Err(err));
+    }
+}
+
+impl Service for MultiplexClient {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        // Caught here as well as in the codec, since an encoding error there
+        // takes the whole connection down with it.
+        if let Err(err) = req.validate() {
+            return Box::pin(future::err(err));
+        }
+        let (tx, rx) = oneshot::channel();
+        if self.requests.send((req, tx)).is_err() {
+            return Box::pin(future::err(broken_pipe()));
+        }
+        Box::pin(rx.map(|result| result.unwrap_or_else(|_| Err(broken_pipe()))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, Bytes, BytesMut};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::api::{Api, ApiHelper};
+    use crate::binary::put_packet;
+    use crate::multiplex::MultiplexClient;
+
+    #[tokio::test]
+    async fn out_of_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Reads a quiet get for "slow" and its no-op, then a version request,
+        // and answers the version first.
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut opaques = Vec::new();
+            for _ in 0..3 {
+                let mut header = [0; 24];
+                stream.read_exact(&mut header).await.unwrap();
+                let mut body = vec![0; (&header[8..12]).get_u32() as usize];
+                stream.read_exact(&mut body).await.unwrap();
+                opaques.push((&header[12..16]).get_u32());
+            }
+            let mut buf = BytesMut::new();
+            put_packet(&mut buf, 0x81, 0x0b, 0, opaques[2], 0, &[], &[], b"1.6.0");
+            put_packet(&mut buf, 0x81, 0x0d, 0, opaques[0], 3, &1u32.to_be_bytes(), b"slow", b"value");
+            put_packet(&mut buf, 0x81, 0x0a, 0, opaques[1], 0, &[], &[], &[]);
+            stream.write_all(&buf).await.unwrap();
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest).await;
+        });
+        let client = MultiplexClient::connect(&addr).await.unwrap();
+        // Requests are sent as soon as they are made, so the get goes first.
+        let slow = client.get_one(String::from("slow"));
+        assert_eq!(client.version().await.unwrap(), "1.6.0");
+        let value = slow.await.unwrap();
+        assert_eq!((value.flags, value.cas, value.value), (1, None, Bytes::from_static(b"value")));
+    }
+
+    #[tokio::test]
+    async fn failed_value() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Fails the quiet get for "big", then answers a version request on
+        // the same connection.
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut opaques = Vec::new();
+            for _ in 0..3 {
+                let mut header = [0; 24];
+                stream.read_exact(&mut header).await.unwrap();
+                let mut body = vec![0; (&header[8..12]).get_u32() as usize];
+                stream.read_exact(&mut body).await.unwrap();
+                opaques.push((&header[12..16]).get_u32());
+            }
+            let mut buf = BytesMut::new();
+            put_packet(&mut buf, 0x81, 0x0d, 0x03, opaques[0], 0, &[], &[], b"Too large.");
+            put_packet(&mut buf, 0x81, 0x0a, 0, opaques[1], 0, &[], &[], &[]);
+            put_packet(&mut buf, 0x81, 0x0b, 0, opaques[2], 0, &[], &[], b"1.6.0");
+            stream.write_all(&buf).await.unwrap();
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest).await;
+        });
+        let client = MultiplexClient::connect(&addr).await.unwrap();
+        let big = client.get(vec![String::from("big")]);
+        let version = client.version();
+        let err = big.await.unwrap_err();
+        assert!(err.to_string().contains("Too large."));
+        assert_eq!(version.await.unwrap(), "1.6.0");
+    }
+}
@@ -0,0 +1,127 @@
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::io;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::key::MAX_KEY_LEN;
+use crate::hash;
+use crate::parse_utils::is_key_char;
+use crate::service::{Service, BoxFuture};
+
+// Length of the `:` and 16 hex digits appended to a shortened key.
+const HASH_SUFFIX_LEN: usize = 17;
+
+// The longest prefix that still leaves room for a shortened key to keep at
+// least one byte of the original.
+pub const MAX_PREFIX_LEN: usize = MAX_KEY_LEN - HASH_SUFFIX_LEN - 1;
+
+// Prefixes every key with a namespace, so that several applications can share
+// a cache without their keys colliding, and strips it again from the keys of
+// returned values.  With `with_hashed_long_keys`, keys that would be too long
+// once prefixed are shortened and suffixed with a hash of the full key instead
+// of being rejected.  The prefix itself must be valid in a key and at most
+// `MAX_PREFIX_LEN` bytes long.
+pub struct Namespaced<S> {
+    inner: S,
+    prefix: String,
+    hash_long_keys: bool,
+}
+
+impl<S> Namespaced<S> {
+    pub fn new<P: Into<String>>(inner: S, prefix: P) -> io::Result<Namespaced<S>> {
+        let prefix = prefix.into();
+        if prefix.len() > MAX_PREFIX_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("namespace prefix longer than {} bytes", MAX_PREFIX_LEN)));
+        }
+        if !prefix.bytes().all(is_key_char) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("namespace prefix contains invalid characters: {:?}", prefix)));
+        }
+        Ok(Namespaced{inner: inner, prefix: prefix, hash_long_keys: false})
+    }
+
+    pub fn with_hashed_long_keys(self) -> Namespaced<S> {
+        Namespaced{hash_long_keys: true, ..self}
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    // The key sent to the server for `key`.
+    pub fn key(&self, key: &str) -> String {
+        if !self.hash_long_keys || self.prefix.len() + key.len() <= MAX_KEY_LEN {
+            return format!("{}{}", self.prefix, key);
+        }
+        let mut len = MAX_KEY_LEN.saturating_sub(self.prefix.len() + HASH_SUFFIX_LEN);
+        while !key.is_char_boundary(len) {
+            len -= 1;
+        }
+        format!("{}{}:{:016x}", self.prefix, &key[..len], hash::fnv1a(key.as_bytes()))
+    }
+}
+
+impl<S> Service for Namespaced<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error>,
+          S::Future: Send + 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        // Remember the original of each key we send, since a hashed key can't
+        // be turned back into it.
+        let mut originals = HashMap::new();
+        let req = req.map_keys(|key| {
+            let namespaced = self.key(&key);
+            originals.insert(namespaced.clone(), key);
+            namespaced
+        });
+        Box::pin(self.inner.call(req).map(move |rsp| {
+            Ok(match rsp? {
+                Response::Values(values) => {
+                    Response::Values(values.into_iter().filter_map(|mut value| {
+                        originals.get(&value.key).map(|key| {
+                            value.key = key.clone();
+                            value
+                        })
+                    }).collect())
+                },
+                rsp => rsp,
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::namespace::{Namespaced, MAX_PREFIX_LEN};
+    use crate::key::{Key, MAX_KEY_LEN};
+
+    #[test]
+    fn key() {
+        let namespaced = Namespaced::new((), "app:").unwrap();
+        assert_eq!(namespaced.key("user:1"), "app:user:1");
+        let long = "k".repeat(MAX_KEY_LEN);
+        assert!(Key::new(namespaced.key(&long)).is_err());
+        let namespaced = namespaced.with_hashed_long_keys();
+        assert_eq!(namespaced.key("user:1"), "app:user:1");
+        let hashed = namespaced.key(&long);
+        assert!(Key::new(hashed.clone()).is_ok());
+        assert!(hashed.starts_with("app:kkk"));
+        assert!(namespaced.key(&format!("{}x", long)) != hashed);
+    }
+
+    #[test]
+    fn prefix() {
+        assert!(Namespaced::new((), "").is_ok());
+        assert!(Namespaced::new((), "my app:").is_err());
+        assert!(Namespaced::new((), "app\n").is_err());
+        assert!(Namespaced::new((), "p".repeat(MAX_PREFIX_LEN + 1)).is_err());
+        let namespaced = Namespaced::new((), "p".repeat(MAX_PREFIX_LEN)).unwrap().with_hashed_long_keys();
+        for len in [1, 10, MAX_KEY_LEN] {
+            assert!(Key::new(namespaced.key(&"k".repeat(len))).is_ok());
+        }
+    }
+}
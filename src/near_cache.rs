@@ -0,0 +1,266 @@
+use futures::{FutureExt, future};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::value::Value;
+use crate::service::{Service, BoxFuture};
+
+struct Entry {
+    value: Value,
+    expires: Instant,
+    tick: u64,
+}
+
+// A bounded map evicting the least recently used entry, with each entry
+// expiring after a fixed time to live.
+//
+// `generation` is bumped by every write, before it is sent and again once it
+// is answered, so that a `get` that was in flight during a write doesn't put
+// the value from before the write into the cache.  It is shared by all keys:
+// a write to one key stops every fill in flight from being cached, which
+// costs a few extra misses but needs no per-key bookkeeping.
+struct Lru {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    generation: u64,
+}
+
+impl Lru {
+    fn new(capacity: usize, ttl: Duration) -> Lru {
+        Lru{capacity: capacity, ttl: ttl, entries: HashMap::new(), order: BTreeMap::new(), tick: 0, generation: 0}
+    }
+
+    fn get(&mut self, key: &str, now: Instant) -> Option<Value> {
+        let tick = self.tick + 1;
+        let (expired, old_tick) = match self.entries.get_mut(key) {
+            Some(entry) => {
+                let old_tick = entry.tick;
+                entry.tick = tick;
+                (entry.expires <= now, old_tick)
+            },
+            None => return None,
+        };
+        self.order.remove(&old_tick);
+        if expired {
+            self.entries.remove(key);
+            return None;
+        }
+        self.tick = tick;
+        self.order.insert(tick, String::from(key));
+        self.entries.get(key).map(|entry| entry.value.clone())
+    }
+
+    fn insert(&mut self, value: Value, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&value.key);
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.order.iter().next() {
+                Some((&tick, _)) => tick,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, value.key.clone());
+        self.entries.insert(value.key.clone(), Entry{value: value, expires: now + self.ttl, tick: self.tick});
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+struct Inner<S> {
+    service: S,
+    cache: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+// A small local cache in front of a remote one, answering `get`s for recently
+// read keys without a round trip.  Entries live for at most `ttl`, and writes
+// made through this client invalidate the keys they touch; writes by other
+// clients are only seen once the local entry expires.  `gets` always goes to
+// the server, since its CAS value must be current.
+pub struct NearCache<S> {
+    inner: Arc<Inner<S>>,
+}
+
+impl<S> NearCache<S> {
+    pub fn new(service: S, capacity: usize, ttl: Duration) -> NearCache<S> {
+        NearCache{
+            inner: Arc::new(Inner{
+                service: service,
+                cache: Mutex::new(Lru::new(capacity, ttl)),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.inner.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.inner.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn clear(&self) {
+        self.inner.cache.lock().unwrap().clear();
+    }
+
+}
+
+fn invalidate<S>(inner: &Inner<S>, req: &Request) {
+    let mut cache = inner.cache.lock().unwrap();
+    cache.generation += 1;
+    match *req {
+        Request::FlushAll{..} => cache.clear(),
+        _ => {
+            if let Some(key) = req.key() {
+                cache.remove(key);
+            }
+        },
+    }
+}
+
+impl<S> Service for NearCache<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+          S::Future: Send + 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let keys = match req {
+            Request::Get{keys} => keys,
+            Request::Gets{..} | Request::Version => return Box::pin(self.inner.service.call(req)),
+            req => {
+                // Invalidated again once answered, in case a `get` sent
+                // meanwhile read the old value and filled the cache with it.
+                invalidate(&self.inner, &req);
+                let inner = self.inner.clone();
+                let rsp = self.inner.service.call(req.clone());
+                return Box::pin(rsp.map(move |rsp| {
+                    invalidate(&inner, &req);
+                    rsp
+                }));
+            },
+        };
+
+        let now = Instant::now();
+        let mut found: HashMap<String, Value> = HashMap::new();
+        let mut missing = Vec::new();
+        let generation = {
+            let mut cache = self.inner.cache.lock().unwrap();
+            for key in keys.iter() {
+                match cache.get(key, now) {
+                    Some(value) => {
+                        found.insert(key.clone(), value);
+                    },
+                    None => missing.push(key.clone()),
+                }
+            }
+            cache.generation
+        };
+        self.inner.hits.fetch_add(found.len() as u64, Ordering::Relaxed);
+        self.inner.misses.fetch_add(missing.len() as u64, Ordering::Relaxed);
+        if missing.is_empty() {
+            return Box::pin(future::ok(Response::Values(keys.iter().filter_map(|key| found.remove(key)).collect())));
+        }
+
+        let inner = self.inner.clone();
+        Box::pin(self.inner.service.call(Request::Get{keys: missing}).map(move |rsp| {
+            Ok(match rsp? {
+                Response::Values(values) => {
+                    let now = Instant::now();
+                    let mut cache = inner.cache.lock().unwrap();
+                    let fresh = cache.generation == generation;
+                    for value in values {
+                        if fresh {
+                            cache.insert(value.clone(), now);
+                        }
+                        found.insert(value.key.clone(), value);
+                    }
+                    Response::Values(keys.iter().filter_map(|key| found.remove(key)).collect())
+                },
+                rsp => rsp,
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::time::{Duration, Instant};
+    use crate::api::Api;
+    use crate::mock::{Expectation, MockService};
+    use crate::near_cache::{Lru, NearCache};
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::value::Value;
+
+    fn value(key: &str) -> Value {
+        Value{key: String::from(key), value: Bytes::copy_from_slice(key.as_bytes()), flags: 0, cas: None}
+    }
+
+    #[test]
+    fn lru() {
+        let now = Instant::now();
+        let mut lru = Lru::new(2, Duration::from_secs(1));
+        lru.insert(value("a"), now);
+        lru.insert(value("b"), now);
+        assert!(lru.get("a", now).is_some());
+        lru.insert(value("c"), now);
+        assert!(lru.get("a", now).is_some());
+        assert!(lru.get("b", now).is_none());
+        assert!(lru.get("c", now).is_some());
+        assert!(lru.get("c", now + Duration::from_secs(1)).is_none());
+        lru.remove("a");
+        assert!(lru.get("a", now).is_none());
+    }
+
+    #[tokio::test]
+    async fn racing_fill() {
+        let get = Request::Get{keys: vec![String::from("a")]};
+        let mock = MockService::new();
+        mock.expect(Expectation::new(get.clone()).respond(Response::Values(vec![value("a")])).after(Duration::from_millis(30)))
+            .expect(Expectation::any().respond(Response::Stored))
+            .expect(Expectation::new(get.clone()).respond(Response::Values(vec![Value{value: Bytes::from_static(b"new"), ..value("a")}])));
+        let cache = NearCache::new(mock.clone(), 10, Duration::from_secs(60));
+        let fill = tokio::spawn({
+            let cache = NearCache{inner: cache.inner.clone()};
+            async move { cache.get(vec![String::from("a")]).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        cache.set(String::from("a"), Bytes::from_static(b"new"), 0, 0).await.unwrap();
+        assert_eq!(&fill.await.unwrap().unwrap()[0].value[..], b"a");
+        assert_eq!(&cache.get(vec![String::from("a")]).await.unwrap()[0].value[..], b"new");
+        assert_eq!(&cache.get(vec![String::from("a")]).await.unwrap()[0].value[..], b"new");
+        mock.verify();
+        assert_eq!(mock.calls().len(), 3);
+        assert_eq!((cache.hits(), cache.misses()), (1, 2));
+    }
+}
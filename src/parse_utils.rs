@@ -1,4 +1,15 @@
+use bytes::Bytes;
+use nom::IResult;
+
 #[inline]
 pub fn is_key_char(chr: u8) -> bool {
     (chr >= 0x21 && chr <= 0x7e)
 }
+
+// Takes a payload of `len` bytes and the line ending after it.
+pub fn payload(input: &[u8], len: u32) -> IResult<&[u8], Bytes> {
+    chain!(input,
+        value: take!(len) ~
+        tag!("\r\n"),
+        || Bytes::copy_from_slice(value))
+}
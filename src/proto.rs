@@ -1,90 +1,394 @@
-use tokio_core::io::{Io, Codec, EasyBuf, Framed};
-use tokio_proto::pipeline::{ServerProto, ClientProto};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use std::cmp;
 use std::io;
 use nom::IResult;
 
-use request::Request;
-use response::Response;
+use crate::request::Request;
+use crate::response::Response;
+use crate::value::Value;
 
-pub struct ClientCodec;
+// The largest value either codec accepts by default, as in memcached.
+pub const MAX_VALUE_LEN: usize = 1024 * 1024;
 
-impl Codec for ClientCodec {
-    type Out = Request;
-    type In = Response;
+// The longest line either codec accepts, including its "\r\n".  Long enough
+// for a `get` of a couple of hundred maximum-length keys.
+pub const MAX_LINE_LEN: usize = 64 * 1024;
 
-    fn encode(&mut self, req: Request, buf: &mut Vec<u8>) -> io::Result<()> {
-        req.build(buf);
-        Ok(())
+// The most buffer space reserved ahead of a payload that is still arriving,
+// so that a peer's claimed length alone never decides how much is allocated.
+pub(crate) const READ_AHEAD: usize = 64 * 1024;
+
+// Splits the next line, including its "\r\n", off the front of `buf`.
+// `scanned` is how much of `buf` is already known not to hold a line ending,
+// so each byte is only searched once however slowly the line arrives.
+fn next_line(buf: &mut BytesMut, scanned: &mut usize) -> io::Result<Option<BytesMut>> {
+    match buf[*scanned..].windows(2).position(|window| window == b"\r\n") {
+        Some(position) => {
+            let end = *scanned + position + 2;
+            *scanned = 0;
+            if end > MAX_LINE_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line longer than {} bytes", MAX_LINE_LEN)));
+            }
+            Ok(Some(buf.split_to(end)))
+        },
+        None if buf.len() >= MAX_LINE_LEN => Err(io::Error::new(io::ErrorKind::InvalidData, format!("line longer than {} bytes", MAX_LINE_LEN))),
+        None => {
+            *scanned = buf.len().saturating_sub(1);
+            Ok(None)
+        },
     }
+}
 
-    fn decode(&mut self, buf: &mut EasyBuf) -> Result<Option<Response>, io::Error> {
-        let buf_len = buf.len();
-        let (result, bytes_used) = match { Response::parse(buf.as_slice()) } {
-            IResult::Done(remaining, rsp) => {
-                (Ok(Some(rsp)), buf_len - remaining.len())
-            },
-            IResult::Error(err) => {
-                (Err(io::Error::new(io::ErrorKind::Other, err)), 0)
-            },
-            IResult::Incomplete(_) => {
-                (Ok(None), 0)
+// Checks a payload length read off the wire against the codec's limit.
+fn check_len(len: u32, max: usize) -> io::Result<usize> {
+    let len = len as usize;
+    if len > max {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("value of {} bytes is over the {} byte limit", len, max)));
+    }
+    Ok(len)
+}
+
+// Parses a complete line, which must be consumed entirely.
+fn parse_line<T>(line: &[u8], parse: fn(&[u8]) -> IResult<&[u8], T>) -> io::Result<T> {
+    match parse(line) {
+        IResult::Done(&[], item) => Ok(item),
+        IResult::Error(err) => Err(io::Error::other(err)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed line {:?}", String::from_utf8_lossy(line)))),
+    }
+}
+
+// Splits a payload of `len` bytes and the "\r\n" after it off the front of
+// `buf`, or makes room for the rest of it to be read.  The payload shares the
+// read buffer rather than being copied out of it.
+fn split_payload(buf: &mut BytesMut, len: usize) -> io::Result<Option<Bytes>> {
+    if buf.len() < len + 2 {
+        buf.reserve(cmp::min(len + 2 - buf.len(), READ_AHEAD));
+        return Ok(None);
+    }
+    if &buf[len..len + 2] != b"\r\n" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "payload is not followed by a line ending"));
+    }
+    let payload = buf.split_to(len).freeze();
+    buf.advance(2);
+    Ok(Some(payload))
+}
+
+// A value read or written a piece at a time: its `VALUE` line, its payload
+// in chunks, then the line ending after the payload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueFrame {
+    Header{key: String, flags: u16, len: usize, cas: Option<u64>},
+    Chunk(Bytes),
+    End,
+}
+
+// How `ClientCodec` decodes the values of a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Streaming {
+    // Together, as a `Response::Values`.
+    #[default]
+    Off,
+    // One at a time, as each is read.
+    Values,
+    // A piece at a time, as each piece is read.
+    Pieces,
+}
+
+// What `ClientCodec` decodes: whole responses, or, while streaming, values
+// either whole or a piece at a time.  A streamed response still ends with an
+// empty `Response::Values`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientFrame {
+    Response(Response),
+    Value(Value),
+    Piece(ValueFrame),
+}
+
+// Decodes responses incrementally: each line is parsed once it is complete,
+// and the parsed header of a value is kept while its payload arrives, so
+// nothing is parsed twice however the response is split across reads.
+pub struct ClientCodec {
+    // The values of a response read so far, and the value whose payload is
+    // still being read along with its length.
+    values: Option<Vec<Value>>,
+    value: Option<(Value, usize)>,
+    // How much of a streamed payload is still to come.
+    remaining: Option<usize>,
+    streaming: Streaming,
+    scanned: usize,
+    max_value_len: usize,
+}
+
+impl ClientCodec {
+    pub fn new() -> ClientCodec {
+        ClientCodec{values: None, value: None, remaining: None, streaming: Streaming::Off, scanned: 0, max_value_len: MAX_VALUE_LEN}
+    }
+
+    // Sets the largest value read whole, past which the response is treated
+    // as corrupt.  Values streamed a piece at a time are never held whole, so
+    // aren't limited.
+    pub fn with_max_value_len(self, max_value_len: usize) -> ClientCodec {
+        ClientCodec{max_value_len: max_value_len, ..self}
+    }
+
+    // Sets how the values of the next responses are decoded.  Only takes
+    // effect between responses.
+    pub fn set_streaming(&mut self, streaming: Streaming) {
+        self.streaming = streaming;
+    }
+
+    fn decode_chunk(&mut self, buf: &mut BytesMut, remaining: usize) -> io::Result<Option<ClientFrame>> {
+        if remaining > 0 {
+            if buf.is_empty() {
+                return Ok(None);
             }
-        };
-        buf.drain_to(bytes_used);
-        result 
+            let len = cmp::min(remaining, buf.len());
+            self.remaining = Some(remaining - len);
+            return Ok(Some(ClientFrame::Piece(ValueFrame::Chunk(buf.split_to(len).freeze()))));
+        }
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        if &buf[..2] != b"\r\n" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "payload is not followed by a line ending"));
+        }
+        buf.advance(2);
+        self.remaining = None;
+        Ok(Some(ClientFrame::Piece(ValueFrame::End)))
     }
 }
 
-pub struct ServerCodec;
+impl Default for ClientCodec {
+    fn default() -> ClientCodec {
+        ClientCodec::new()
+    }
+}
 
-impl Codec for ServerCodec {
-    type In = Request;
-    type Out = Response;
+impl Encoder<Request> for ClientCodec {
+    type Error = io::Error;
 
-    fn decode(&mut self, buf: &mut EasyBuf) -> Result<Option<Request>, io::Error> {
-        let buf_len = buf.len();
-        let (result, bytes_used) = match { Request::parse(buf.as_slice()) } {
-            IResult::Done(remaining, req) => {
-                (Ok(Some(req)), buf_len - remaining.len())
-            },
-            IResult::Error(err) => {
-                (Err(io::Error::new(io::ErrorKind::Other, err)), 0)
-            },
-            IResult::Incomplete(_) => {
-                (Ok(None), 0)
+    fn encode(&mut self, req: Request, buf: &mut BytesMut) -> io::Result<()> {
+        req.build(buf)
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Item = ClientFrame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<ClientFrame>, io::Error> {
+        loop {
+            if let Some(remaining) = self.remaining {
+                return self.decode_chunk(buf, remaining);
+            }
+            if let Some((_, len)) = self.value {
+                let payload = match split_payload(buf, len)? {
+                    Some(payload) => payload,
+                    None => return Ok(None),
+                };
+                let (value, _) = self.value.take().expect("value header missing");
+                let value = Value{value: payload, ..value};
+                if self.streaming == Streaming::Values {
+                    return Ok(Some(ClientFrame::Value(value)));
+                }
+                self.values.get_or_insert_with(Vec::new).push(value);
+            }
+            let line = match next_line(buf, &mut self.scanned)? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            if line.starts_with(b"VALUE ") {
+                let (value, len) = parse_line(&line, Value::parse_header)?;
+                self.values.get_or_insert_with(Vec::new);
+                if self.streaming == Streaming::Pieces {
+                    self.remaining = Some(len as usize);
+                    return Ok(Some(ClientFrame::Piece(ValueFrame::Header{key: value.key, flags: value.flags, len: len as usize, cas: value.cas})));
+                }
+                self.value = Some((value, check_len(len, self.max_value_len)?));
+            } else if &line[..] == b"END\r\n" {
+                return Ok(Some(ClientFrame::Response(Response::Values(self.values.take().unwrap_or_default()))));
+            } else if self.values.is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "values not followed by END"));
+            } else {
+                return parse_line(&line, Response::parse).map(|rsp| Some(ClientFrame::Response(rsp)));
             }
-        };
-        buf.drain_to(bytes_used);
-        result 
+        }
+    }
+}
+
+// Decodes requests incrementally, keeping the parsed command line of a
+// storage command while its payload arrives.  A payload longer than the
+// maximum value length is refused before any of it is buffered.
+pub struct ServerCodec {
+    // A storage command whose payload is still being read, and its length.
+    header: Option<(Request, usize)>,
+    scanned: usize,
+    max_value_len: usize,
+}
+
+impl ServerCodec {
+    pub fn new() -> ServerCodec {
+        ServerCodec{header: None, scanned: 0, max_value_len: MAX_VALUE_LEN}
     }
 
-    fn encode(&mut self, rsp: Response, buf: &mut Vec<u8>) -> io::Result<()> {
+    pub fn with_max_value_len(self, max_value_len: usize) -> ServerCodec {
+        ServerCodec{max_value_len: max_value_len, ..self}
+    }
+}
+
+impl Default for ServerCodec {
+    fn default() -> ServerCodec {
+        ServerCodec::new()
+    }
+}
+
+impl Decoder for ServerCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Request>, io::Error> {
+        if self.header.is_none() {
+            let line = match next_line(buf, &mut self.scanned)? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            match parse_line(&line, Request::parse_header)? {
+                (req, None) => return Ok(Some(req)),
+                (req, Some(len)) => self.header = Some((req, check_len(len, self.max_value_len)?)),
+            }
+        }
+        let len = self.header.as_ref().map_or(0, |&(_, len)| len);
+        match split_payload(buf, len)? {
+            Some(payload) => {
+                let (req, _) = self.header.take().expect("request header missing");
+                Ok(Some(req.with_value(payload)))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Response> for ServerCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, rsp: Response, buf: &mut BytesMut) -> io::Result<()> {
         rsp.build(buf);
         Ok(())
     }
 }
 
+// Writes a value a piece at a time.  The values of a response are followed
+// by an empty `Response::Values` to end it.
+impl Encoder<ValueFrame> for ServerCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: ValueFrame, buf: &mut BytesMut) -> io::Result<()> {
+        match frame {
+            ValueFrame::Header{key, flags, len, cas} => Value::build_header(&key, flags, len, cas, buf),
+            ValueFrame::Chunk(chunk) => buf.extend_from_slice(&chunk),
+            ValueFrame::End => buf.extend_from_slice(b"\r\n"),
+        }
+        Ok(())
+    }
+}
+
+// Frames a connection with the memcached text protocol.
 pub struct Proto;
 
-impl<T: Io + 'static> ClientProto<T> for Proto {
-    type Request = Request;
-    type Response = Response;
-    type Transport = Framed<T, ClientCodec>;
-    type BindTransport = Result<Self::Transport, io::Error>;
+impl Proto {
+    pub fn client<T: AsyncRead + AsyncWrite>(&self, io: T) -> Framed<T, ClientCodec> {
+        Framed::new(io, ClientCodec::new())
+    }
 
-    fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(ClientCodec))
+    pub fn server<T: AsyncRead + AsyncWrite>(&self, io: T) -> Framed<T, ServerCodec> {
+        Framed::new(io, ServerCodec::new())
     }
 }
 
-impl<T: Io + 'static> ServerProto<T> for Proto {
-    type Request = Request;
-    type Response = Response;
-    type Transport = Framed<T, ServerCodec>;
-    type BindTransport = io::Result<Framed<T, ServerCodec>>;
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use tokio_util::codec::Decoder;
+    use std::io;
+    use crate::proto::{ClientCodec, ClientFrame, ServerCodec, Streaming, ValueFrame, MAX_LINE_LEN};
+    use crate::request::Request;
+    use crate::response::Response;
+
+    #[test]
+    fn decode_in_pieces() {
+        let input = b"VALUE a 1 5\r\nvalue\r\nVALUE b 0 3 7\r\nabc\r\nEND\r\n42\r\nSTORED\r\n";
+        let mut codec = ClientCodec::new();
+        let mut buf = BytesMut::new();
+        let mut responses = Vec::new();
+        for byte in input.iter() {
+            buf.extend_from_slice(&[*byte]);
+            while let Some(frame) = codec.decode(&mut buf).unwrap() {
+                match frame {
+                    ClientFrame::Response(rsp) => responses.push(rsp),
+                    frame => panic!("unexpected frame {:?}", frame),
+                }
+            }
+        }
+        assert_eq!(responses.len(), 3);
+        match responses[0] {
+            Response::Values(ref values) => {
+                assert_eq!((values[0].key.as_str(), &values[0].value[..], values[0].flags), ("a", &b"value"[..], 1));
+                assert_eq!((values[1].key.as_str(), &values[1].value[..], values[1].cas), ("b", &b"abc"[..], Some(7)));
+            },
+            ref rsp => panic!("unexpected response {:?}", rsp),
+        }
+        assert_eq!(responses[1], Response::UpdatedValue(42));
+        assert_eq!(responses[2], Response::Stored);
+
+        let mut codec = ClientCodec::new();
+        codec.set_streaming(Streaming::Pieces);
+        let mut buf = BytesMut::from(&b"VALUE a 1 5\r\nval"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(ClientFrame::Piece(ValueFrame::Header{key: String::from("a"), flags: 1, len: 5, cas: None})));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(ClientFrame::Piece(ValueFrame::Chunk(Bytes::from_static(b"val")))));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"ue\r\nEND\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(ClientFrame::Piece(ValueFrame::Chunk(Bytes::from_static(b"ue")))));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(ClientFrame::Piece(ValueFrame::End)));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(ClientFrame::Response(Response::Values(Vec::new()))));
 
-    fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(ServerCodec))
+        let mut buf = BytesMut::from(&b"set k 0 0 5\r\nhello\r\nget k\r\n"[..]);
+        let start = buf.as_ptr() as usize;
+        let mut codec = ServerCodec::new();
+        match codec.decode(&mut buf).unwrap() {
+            Some(Request::Set{ref value, ..}) => {
+                assert_eq!(&value[..], b"hello");
+                // The payload is a slice of the read buffer, not a copy.
+                assert_eq!(value.as_ptr() as usize, start + 13);
+            },
+            req => panic!("unexpected request {:?}", req),
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Request::Get{keys: vec![String::from("k")]}));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
     }
-}
 
+    #[test]
+    fn limits() {
+        // A claimed length is refused before anything is reserved for it.
+        let mut buf = BytesMut::from(&b"set k 0 0 4294967295\r\n"[..]);
+        let err = ServerCodec::new().decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(buf.capacity() < 1024);
+        let mut buf = BytesMut::from(&b"set k 0 0 10\r\n"[..]);
+        assert!(ServerCodec::new().with_max_value_len(8).decode(&mut buf).is_err());
+        // One within the limit still only reserves a chunk at a time.
+        let mut buf = BytesMut::from(&b"set k 0 0 1000000\r\n"[..]);
+        assert_eq!(ServerCodec::new().decode(&mut buf).unwrap(), None);
+        assert!(buf.capacity() <= 128 * 1024);
+
+        let mut buf = BytesMut::from(&b"VALUE a 0 2000000\r\n"[..]);
+        assert!(ClientCodec::new().decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::from(&vec![b'a'; MAX_LINE_LEN][..]);
+        assert!(ServerCodec::new().decode(&mut buf).is_err());
+        let mut line = vec![b'a'; MAX_LINE_LEN];
+        line.extend_from_slice(b"\r\n");
+        assert!(ClientCodec::new().decode(&mut BytesMut::from(&line[..])).is_err());
+    }
+}
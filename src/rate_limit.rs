@@ -0,0 +1,319 @@
+use futures::future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::api::Api;
+use crate::counter::Counter;
+use crate::request::Request;
+use crate::response::Response;
+use crate::layer::Layer;
+use crate::service::{Service, BoxFuture};
+
+// The outcome of a rate limit check.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quota {
+    pub allowed: bool,
+    // How many more units may be used before the limit is reached.
+    pub remaining: u64,
+    // How long until the limit would allow this request.  For an allowed
+    // request, how long until the current window ends.
+    pub reset_after: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Window {
+    Fixed,
+    Sliding,
+}
+
+// Limits each id to `limit` units per `window` seconds, counted in memcached
+// so that the limit holds across processes.
+//
+// A fixed window counts in consecutive windows, so up to twice the limit can
+// be used across the boundary between two windows.  A sliding window avoids
+// that by also weighting the count from the previous window by how much of it
+// still overlaps the last `window` seconds.
+//
+// Every check is counted, including those that are refused, so a client that
+// keeps retrying stays limited.
+pub struct RateLimiter<A> {
+    api: Arc<A>,
+    prefix: String,
+    limit: u64,
+    window: u32,
+    kind: Window,
+}
+
+impl<A> RateLimiter<A> {
+    pub fn fixed<P: Into<String>>(api: A, prefix: P, limit: u64, window: u32) -> RateLimiter<A> {
+        RateLimiter{api: Arc::new(api), prefix: prefix.into(), limit: limit, window: window.max(1), kind: Window::Fixed}
+    }
+
+    pub fn sliding<P: Into<String>>(api: A, prefix: P, limit: u64, window: u32) -> RateLimiter<A> {
+        RateLimiter{kind: Window::Sliding, ..RateLimiter::fixed(api, prefix, limit, window)}
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    fn counter(&self, id: &str, index: u64) -> Counter<A> {
+        // Sliding windows read the previous window's count, so keep it for
+        // one more window.
+        let expiry = match self.kind {
+            Window::Fixed => self.window + 1,
+            Window::Sliding => self.window * 2 + 1,
+        };
+        Counter::shared(self.api.clone(), format!("{}:{}:{}", self.prefix, id, index), expiry)
+    }
+}
+
+impl<A: Api<io::Error>> RateLimiter<A> {
+    pub async fn check(&self, id: &str) -> io::Result<Quota> {
+        self.check_n(id, 1).await
+    }
+
+    // Uses `cost` units of `id`'s quota.
+    pub async fn check_n(&self, id: &str, cost: u64) -> io::Result<Quota> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let window = Duration::from_secs(self.window as u64);
+        let index = now.as_secs() / self.window as u64;
+        let elapsed = Duration::new(now.as_secs() % self.window as u64, now.subsec_nanos());
+        let current = self.counter(id, index);
+        match self.kind {
+            Window::Fixed => Ok(fixed(self.limit, current.incr(cost).await?, window, elapsed)),
+            Window::Sliding => {
+                let previous = self.counter(id, index.wrapping_sub(1));
+                let (count, previous) = future::try_join(current.incr(cost), previous.get()).await?;
+                Ok(sliding(self.limit, previous, count, window, elapsed))
+            },
+        }
+    }
+}
+
+fn fixed(limit: u64, count: u64, window: Duration, elapsed: Duration) -> Quota {
+    Quota{allowed: count <= limit, remaining: limit.saturating_sub(count), reset_after: window - elapsed}
+}
+
+fn sliding(limit: u64, previous: u64, count: u64, window: Duration, elapsed: Duration) -> Quota {
+    let window_secs = window.as_secs() as f64;
+    let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    let weight = 1.0 - elapsed_secs / window_secs;
+    let estimate = previous as f64 * weight + count as f64;
+    let limit_f = limit as f64;
+    if estimate <= limit_f {
+        return Quota{allowed: true, remaining: (limit_f - estimate) as u64, reset_after: window - elapsed};
+    }
+    // Find when enough of the older counts will have slid out of the window.
+    let wait = if count < limit {
+        // Later in this window, once the previous window's share shrinks.
+        window_secs * (1.0 - (limit_f - count as f64) / previous as f64) - elapsed_secs
+    } else {
+        // In the next window, once this window's share shrinks.
+        window_secs - elapsed_secs + window_secs * (1.0 - limit_f / count as f64)
+    };
+    Quota{allowed: false, remaining: 0, reset_after: Duration::from_millis((wait.max(0.0) * 1000.0).round() as u64)}
+}
+
+// A token bucket holding up to `burst` tokens, refilled at `rate` a second.
+// It may also go up to `burst` tokens into debt, for requests waiting their
+// turn.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u32, burst: u32, now: Instant) -> Bucket {
+        let burst = burst.max(1) as f64;
+        Bucket{rate: rate.max(1) as f64, burst: burst, tokens: burst, last: now}
+    }
+
+    // Takes a token, returning how long to wait until it is due, or `None`
+    // if the bucket is already as far in debt as it may go.  Tokens are taken
+    // ahead of time when the bucket is empty, so waiting requests go in turn.
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = self.last.max(now);
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        if self.tokens - 1.0 < -self.burst {
+            return None;
+        }
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Some(Duration::ZERO)
+        } else {
+            Some(Duration::from_secs_f64(-self.tokens / self.rate))
+        }
+    }
+}
+
+// Holds back requests to at most `rate` a second, after an initial burst of
+// up to `burst`.  Unlike `RateLimiter`, this limits one process's requests
+// locally rather than counting in memcached, and requests over the limit wait
+// rather than being refused, up to `burst` of them at a time; any more fail
+// with `WouldBlock` straight away.  A request takes its turn when first
+// polled, not when it is made.  Services made by the same `RateLimitLayer`
+// share one limit.
+pub struct RateLimit<S> {
+    inner: Arc<S>,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl<S> RateLimit<S> {
+    pub fn new(inner: S, rate: u32, burst: u32) -> RateLimit<S> {
+        RateLimit::shared(inner, Arc::new(Mutex::new(Bucket::new(rate, burst, Instant::now()))))
+    }
+
+    fn shared(inner: S, bucket: Arc<Mutex<Bucket>>) -> RateLimit<S> {
+        RateLimit{inner: Arc::new(inner), bucket: bucket}
+    }
+}
+
+impl<S> Service for RateLimit<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+          S::Future: Send {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let bucket = self.bucket.clone();
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let wait = bucket.lock().unwrap().take(Instant::now());
+            match wait {
+                Some(wait) if wait > Duration::ZERO => tokio::time::sleep(wait).await,
+                Some(_) => {},
+                None => return Err(io::Error::new(io::ErrorKind::WouldBlock, format!("{} rate limited", req.command()))),
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(rate: u32, burst: u32) -> RateLimitLayer {
+        RateLimitLayer{bucket: Arc::new(Mutex::new(Bucket::new(rate, burst, Instant::now())))}
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> RateLimit<S> {
+        RateLimit::shared(inner, self.bucket.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::{Duration, Instant};
+    use crate::api::Api;
+    use crate::memory::InMemory;
+    use crate::mock::{Expectation, MockService};
+    use crate::rate_limit::{fixed, sliding, Bucket, RateLimit, RateLimiter};
+    use crate::response::Response;
+
+    #[test]
+    fn fixed_window() {
+        let window = Duration::from_secs(60);
+        let quota = fixed(10, 1, window, Duration::ZERO);
+        assert!(quota.allowed);
+        assert_eq!(quota.remaining, 9);
+        assert_eq!(quota.reset_after, window);
+        // The last unit of the window, just before it ends.
+        let quota = fixed(10, 10, window, Duration::from_millis(59_999));
+        assert!(quota.allowed);
+        assert_eq!(quota.remaining, 0);
+        assert_eq!(quota.reset_after, Duration::from_millis(1));
+        let quota = fixed(10, 11, window, Duration::from_millis(59_999));
+        assert!(!quota.allowed);
+        assert_eq!(quota.remaining, 0);
+        assert_eq!(quota.reset_after, Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn check_over_limit() {
+        for limiter in [RateLimiter::fixed(InMemory::new(), "rl", 5, 3600), RateLimiter::sliding(InMemory::new(), "rl", 5, 3600)] {
+            // More than the whole limit at once is refused, and still counted.
+            let quota = limiter.check_n("id", 10).await.unwrap();
+            assert!(!quota.allowed);
+            assert_eq!(quota.remaining, 0);
+            assert!(!limiter.check("id").await.unwrap().allowed);
+            // Other ids are unaffected.
+            let quota = limiter.check_n("other", 5).await.unwrap();
+            assert!(quota.allowed);
+            assert_eq!(quota.remaining, 0);
+        }
+    }
+
+    #[test]
+    fn sliding_window() {
+        let window = Duration::from_secs(60);
+        // Half the previous window's 10 still counts, plus 4 in this one.
+        let quota = sliding(10, 10, 4, window, Duration::from_secs(30));
+        assert!(quota.allowed);
+        assert_eq!(quota.remaining, 1);
+        assert_eq!(quota.reset_after, Duration::from_secs(30));
+        // 7.5 + 4 is over, until the previous window's share drops to 6.
+        let quota = sliding(10, 10, 4, window, Duration::from_secs(15));
+        assert!(!quota.allowed);
+        assert_eq!(quota.remaining, 0);
+        assert_eq!(quota.reset_after, Duration::from_secs(9));
+        // This window alone is over, until its share drops to 10 in the next.
+        let quota = sliding(10, 0, 20, window, Duration::from_secs(15));
+        assert!(!quota.allowed);
+        assert_eq!(quota.reset_after, Duration::from_secs(75));
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(10, 2, start);
+        assert_eq!(bucket.take(start), Some(Duration::ZERO));
+        assert_eq!(bucket.take(start), Some(Duration::ZERO));
+        // Empty, so each further request waits a tenth of a second longer,
+        // until the debt reaches the burst.
+        assert_eq!(bucket.take(start).unwrap().as_millis(), 100);
+        assert_eq!(bucket.take(start).unwrap().as_millis(), 200);
+        assert_eq!(bucket.take(start), None);
+        assert_eq!(bucket.take(start + Duration::from_millis(100)).unwrap().as_millis(), 200);
+        // Refilled, but only up to the burst.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(later), Some(Duration::ZERO));
+        assert_eq!(bucket.take(later), Some(Duration::ZERO));
+        assert_eq!(bucket.take(later).unwrap().as_millis(), 100);
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let mock = MockService::new();
+        for _ in 0..2 {
+            mock.expect(Expectation::any().respond(Response::Deleted));
+        }
+        let service = RateLimit::new(mock.clone(), 100, 1);
+        // Requests made but never polled don't use up the bucket.
+        drop((0..10).map(|i| service.delete(i.to_string())).collect::<Vec<_>>());
+        let start = Instant::now();
+        service.delete(String::from("a")).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(10));
+        // One request may wait for the next token; the one after fails.
+        let (first, second) = tokio::join!(service.delete(String::from("b")), service.delete(String::from("c")));
+        first.unwrap();
+        assert_eq!(second.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        mock.verify();
+    }
+}
@@ -0,0 +1,227 @@
+use futures::{FutureExt, future};
+use tokio::sync::oneshot;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::client::Client;
+use crate::backoff::Backoff;
+use crate::service::{Service, BoxFuture};
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+enum State {
+    Disconnected{retry_at: Option<Instant>},
+    Connecting(Vec<(Request, oneshot::Sender<Result<Response, io::Error>>)>),
+    Connected{client: Client, generation: u64},
+}
+
+struct Inner {
+    addr: SocketAddr,
+    backoff: Backoff,
+    connect_timeout: Duration,
+    failures: u32,
+    generation: u64,
+    state: State,
+}
+
+// A client that connects lazily on first use and, if the connection drops,
+// reconnects on the next call.  Failed connection attempts are spaced out by
+// the backoff; calls made while waiting to retry fail with `NotConnected`.
+// An attempt taking longer than the connect timeout fails with `TimedOut`.
+// Only errors from the connection itself drop it, not those for a single
+// request such as an invalid key.
+#[derive(Clone)]
+pub struct ReconnectingClient {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ReconnectingClient {
+    pub fn new(addr: &SocketAddr) -> ReconnectingClient {
+        ReconnectingClient::with_backoff(addr, Backoff::default())
+    }
+
+    pub fn with_backoff(addr: &SocketAddr, backoff: Backoff) -> ReconnectingClient {
+        ReconnectingClient{
+            inner: Arc::new(Mutex::new(Inner{
+                addr: *addr,
+                backoff: backoff,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                failures: 0,
+                generation: 0,
+                state: State::Disconnected{retry_at: None},
+            })),
+        }
+    }
+
+    pub fn with_connect_timeout(self, timeout: Duration) -> ReconnectingClient {
+        self.inner.lock().unwrap().connect_timeout = timeout;
+        self
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        match self.inner.lock().unwrap().state {
+            State::Disconnected{..} => ConnectionState::Disconnected,
+            State::Connecting(_) => ConnectionState::Connecting,
+            State::Connected{..} => ConnectionState::Connected,
+        }
+    }
+
+    // Connects on a spawned task, then sends the requests queued meanwhile.
+    fn connect(&self, addr: SocketAddr, timeout: Duration) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let result = match tokio::time::timeout(timeout, Client::connect(&addr)).await {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("connecting to {} timed out", addr))),
+            };
+            let (pending, err) = {
+                let mut inner = this.inner.lock().unwrap();
+                let (state, err) = match result {
+                    Ok(client) => {
+                        inner.failures = 0;
+                        inner.generation += 1;
+                        (State::Connected{client: client, generation: inner.generation}, None)
+                    },
+                    Err(err) => {
+                        let delay = inner.backoff.delay(inner.failures);
+                        inner.failures += 1;
+                        (State::Disconnected{retry_at: Some(Instant::now() + delay)}, Some(err))
+                    },
+                };
+                match mem::replace(&mut inner.state, state) {
+                    State::Connecting(pending) => (pending, err),
+                    _ => (Vec::new(), err),
+                }
+            };
+            match err {
+                None => {
+                    for (req, tx) in pending {
+                        tokio::spawn(this.call(req).map(move |result| {
+                            let _ = tx.send(result);
+                        }));
+                    }
+                },
+                Some(err) => {
+                    for (_, tx) in pending {
+                        let _ = tx.send(Err(io::Error::new(err.kind(), err.to_string())));
+                    }
+                },
+            }
+        });
+    }
+
+    fn disconnected(&self, generation: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let current = match inner.state {
+            State::Connected{generation: current, ..} => current == generation,
+            _ => false,
+        };
+        if current {
+            inner.state = State::Disconnected{retry_at: None};
+        }
+    }
+}
+
+// Whether an error means the connection is gone, rather than that one
+// request failed.  `InvalidData` is a response that couldn't be decoded,
+// after which the client closes the connection.
+fn is_transport(err: &io::Error) -> bool {
+    matches!(err.kind(),
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::UnexpectedEof |
+        io::ErrorKind::NotConnected |
+        io::ErrorKind::InvalidData)
+}
+
+impl Service for ReconnectingClient {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        // The state is checked and updated under a single lock, so a call
+        // can't queue on a connection attempt that has already finished.
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.inner.lock().unwrap();
+        let (addr, timeout) = (inner.addr, inner.connect_timeout);
+        match inner.state {
+            State::Connected{ref client, generation} => {
+                let rsp = client.call(req);
+                let this = self.clone();
+                return Box::pin(rsp.map(move |result| {
+                    match result {
+                        Err(ref err) if is_transport(err) => this.disconnected(generation),
+                        _ => {},
+                    }
+                    result
+                }));
+            },
+            State::Disconnected{retry_at: Some(retry_at)} if Instant::now() < retry_at => {
+                return Box::pin(future::err(io::Error::new(io::ErrorKind::NotConnected, "waiting to reconnect")));
+            },
+            State::Disconnected{..} => {
+                inner.state = State::Connecting(vec![(req, tx)]);
+                drop(inner);
+                self.connect(addr, timeout);
+            },
+            State::Connecting(ref mut pending) => pending.push((req, tx)),
+        }
+        Box::pin(rx.map(|result| {
+            match result {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection attempt abandoned")),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+    use std::io;
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Duration;
+    use crate::api::Api;
+    use crate::backoff::Backoff;
+    use crate::memory::InMemory;
+    use crate::reconnect::{ConnectionState, ReconnectingClient};
+    use crate::server::{ApiService, Server};
+
+    #[tokio::test]
+    async fn reconnect() {
+        let addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let client = ReconnectingClient::with_backoff(&addr, Backoff::new(Duration::from_millis(40), Duration::from_millis(40)));
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+        let results = future::join_all((0..3).map(|_| client.version())).await;
+        assert!(results.iter().all(|result| result.as_ref().unwrap_err().kind() == io::ErrorKind::ConnectionRefused));
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+        assert_eq!(client.version().await.unwrap_err().kind(), io::ErrorKind::NotConnected);
+
+        tokio::spawn(Server::new(|| Ok(ApiService::new(InMemory::new()))).serve(addr));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let results = future::join_all((0..3).map(|i| client.set(i.to_string(), Bytes::from_static(b"1"), 0, 0))).await;
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(client.state(), ConnectionState::Connected);
+        assert_eq!(client.get(vec![String::from("0"), String::from("2")]).await.unwrap().len(), 2);
+
+        // A request refused before it is sent leaves the connection be.
+        assert_eq!(client.get(vec![String::from("bad key")]).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(client.state(), ConnectionState::Connected);
+    }
+}
@@ -0,0 +1,302 @@
+use futures::{StreamExt, future};
+use futures::stream::FuturesUnordered;
+use std::cmp;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::value::Value;
+use crate::ring::Ring;
+use crate::service::{Service, BoxFuture};
+
+// Spreads keys over several servers with a consistent hash ring, keeping
+// copies of each key on `replicas` consecutive nodes.  `set`, `delete` and
+// `touch` go to every replica and succeed once `write_quorum` of them have
+// acknowledged; writes still outstanding by then are left to finish in the
+// background.  Reads go to the primary and fall back to the next replica on
+// a miss or failure.  `flush_all` goes to every server and fails if any of
+// them does.  Other requests are sent to the primary only.
+pub struct Replicated<S> {
+    inner: Arc<Inner<S>>,
+}
+
+struct Inner<S> {
+    servers: Vec<S>,
+    ring: Ring,
+    replicas: usize,
+    write_quorum: usize,
+}
+
+impl<S> Replicated<S> {
+    pub fn new(servers: Vec<(String, S)>, replicas: usize, write_quorum: usize) -> Replicated<S> {
+        let (names, servers): (Vec<String>, Vec<S>) = servers.into_iter().unzip();
+        let replicas = cmp::max(cmp::min(replicas, servers.len()), 1);
+        Replicated{
+            inner: Arc::new(Inner{
+                servers: servers,
+                ring: Ring::new(&names),
+                replicas: replicas,
+                write_quorum: cmp::max(cmp::min(write_quorum, replicas), 1),
+            }),
+        }
+    }
+}
+
+impl<S> Replicated<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+          S::Future: Send + 'static {
+    fn write(&self, key: String, req: Request) -> BoxFuture<Response> {
+        let quorum = self.inner.write_quorum;
+        let targets = self.inner.ring.nodes(&key, self.inner.replicas);
+        let replicas = targets.len();
+        let mut responses: FuturesUnordered<_> = targets.into_iter().map(|node| {
+            self.inner.servers[node].call(req.clone())
+        }).collect();
+        Box::pin(async move {
+            let mut acks = Vec::new();
+            let mut answered = 0;
+            let mut last_err = None;
+            loop {
+                match responses.next().await {
+                    Some(Ok(rsp)) => {
+                        if acknowledges(&req, &rsp) {
+                            acks.push(rsp);
+                        } else {
+                            last_err = Some(io::Error::other(format!("unexpected response {:?}", rsp)));
+                        }
+                    },
+                    Some(Err(err)) => last_err = Some(err),
+                    None => {},
+                }
+                answered += 1;
+                if acks.len() >= quorum {
+                    finish(responses);
+                    // Prefer the answer of a replica that actually held the key.
+                    let index = acks.iter().position(|rsp| !matches!(*rsp, Response::NotFound)).unwrap_or(0);
+                    return Ok(acks.swap_remove(index));
+                }
+                if acks.len() + replicas - cmp::min(answered, replicas) < quorum {
+                    finish(responses);
+                    let err = last_err.unwrap_or_else(|| io::Error::other("no replicas"));
+                    return Err(io::Error::new(err.kind(), format!("write quorum of {}/{} not reached: {}", quorum, replicas, err)));
+                }
+            }
+        })
+    }
+
+    fn flush(&self, req: Request) -> BoxFuture<Response> {
+        let requests: Vec<_> = self.inner.servers.iter().map(|server| server.call(req.clone())).collect();
+        Box::pin(async move {
+            let servers = requests.len();
+            let mut failed = 0;
+            let mut last_err = None;
+            for result in future::join_all(requests).await {
+                match result {
+                    Ok(Response::Ok) => {},
+                    Ok(rsp) => {
+                        failed += 1;
+                        last_err = Some(io::Error::other(format!("unexpected response {:?}", rsp)));
+                    },
+                    Err(err) => {
+                        failed += 1;
+                        last_err = Some(err);
+                    },
+                }
+            }
+            match last_err {
+                Some(err) => Err(io::Error::new(err.kind(), format!("flush_all failed on {}/{} servers: {}", failed, servers, err))),
+                None => Ok(Response::Ok),
+            }
+        })
+    }
+
+    fn read(&self, keys: Vec<String>, cas: bool) -> BoxFuture<Response> {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let mut found = read(&inner, keys.clone(), cas).await?;
+            Ok(Response::Values(keys.iter().filter_map(|key| found.remove(key)).collect()))
+        })
+    }
+
+    fn primary(&self, key: &str, req: Request) -> BoxFuture<Response> {
+        match self.inner.ring.primary(key) {
+            Some(node) => Box::pin(self.inner.servers[node].call(req)),
+            None => Box::pin(future::err(io::Error::new(io::ErrorKind::NotConnected, "no servers"))),
+        }
+    }
+}
+
+// Drives writes still outstanding once the outcome is known, so every
+// replica gets them even though nobody waits for the answers.
+fn finish<F>(responses: FuturesUnordered<F>)
+    where F: Future + Send + 'static {
+    if !responses.is_empty() {
+        tokio::spawn(responses.for_each(|_| future::ready(())));
+    }
+}
+
+fn acknowledges(req: &Request, rsp: &Response) -> bool {
+    matches!((req, rsp),
+        (&Request::Set{..}, &Response::Stored) |
+        (&Request::Delete{..}, &Response::Deleted) |
+        (&Request::Delete{..}, &Response::NotFound) |
+        (&Request::Touch{..}, &Response::Touched))
+}
+
+// Fetches `keys` from their first replica, grouping keys by server, and
+// retries whatever was missing or failed on the following replica, and so on.
+// If nothing was found and the last server asked failed, its error is
+// reported rather than a miss.
+async fn read<S>(inner: &Inner<S>, mut keys: Vec<String>, cas: bool) -> io::Result<HashMap<String, Value>>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> {
+    let mut found = HashMap::new();
+    let mut last_err = None;
+    for round in 0..inner.replicas {
+        if keys.is_empty() {
+            break;
+        }
+        let mut groups: HashMap<usize, Vec<String>> = HashMap::new();
+        for key in keys.drain(..) {
+            if let Some(&node) = inner.ring.nodes(&key, inner.replicas).get(round) {
+                groups.entry(node).or_default().push(key);
+            }
+        }
+        let requests = groups.into_iter().map(|(node, keys)| {
+            let req = if cas { Request::Gets{keys: keys.clone()} } else { Request::Get{keys: keys.clone()} };
+            let rsp = inner.servers[node].call(req);
+            async move { (keys, rsp.await) }
+        });
+        for (group, result) in future::join_all(requests).await {
+            match result {
+                Ok(Response::Values(values)) => {
+                    last_err = None;
+                    for value in values {
+                        found.insert(value.key.clone(), value);
+                    }
+                    keys.extend(group.into_iter().filter(|key| !found.contains_key(key)));
+                },
+                Ok(rsp) => {
+                    last_err = Some(io::Error::other(format!("unexpected response {:?}", rsp)));
+                    keys.extend(group);
+                },
+                Err(err) => {
+                    last_err = Some(err);
+                    keys.extend(group);
+                },
+            }
+        }
+    }
+    match last_err {
+        Some(err) if found.is_empty() => Err(err),
+        _ => Ok(found),
+    }
+}
+
+impl<S> Service for Replicated<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+          S::Future: Send + 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        match req {
+            Request::Get{keys} => self.read(keys, false),
+            Request::Gets{keys} => self.read(keys, true),
+            Request::Set{..} | Request::Delete{..} | Request::Touch{..} => {
+                let key = String::from(req.key().unwrap_or(""));
+                self.write(key, req)
+            },
+            Request::FlushAll{..} => self.flush(req),
+            Request::Version => match self.inner.servers.first() {
+                Some(server) => Box::pin(server.call(req)),
+                None => Box::pin(future::err(io::Error::new(io::ErrorKind::NotConnected, "no servers"))),
+            },
+            _ => {
+                let key = String::from(req.key().unwrap_or(""));
+                self.primary(&key, req)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::io;
+    use std::time::Duration;
+    use crate::api::Api;
+    use crate::memory::InMemory;
+    use crate::mock::MockService;
+    use crate::replicated::Replicated;
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::service::{Service, BoxFuture};
+
+    // Answers from an `InMemory` only once polled, after a delay.
+    struct Slow(InMemory);
+
+    impl Service for Slow {
+        type Request = Request;
+        type Response = Response;
+        type Error = io::Error;
+        type Future = BoxFuture<Response>;
+
+        fn call(&self, req: Request) -> Self::Future {
+            let cache = self.0.clone();
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                cache.call(req).await
+            })
+        }
+    }
+
+    fn servers(down: MockService, up: MockService) -> Vec<(String, MockService)> {
+        vec![(String::from("a"), down), (String::from("b"), up)]
+    }
+
+    #[tokio::test]
+    async fn quorum() {
+        // The first server fails every request.
+        let up = MockService::new().with_fallback(InMemory::new());
+        let cache = Replicated::new(servers(MockService::new(), up.clone()), 2, 1);
+        cache.set(String::from("k"), Bytes::from_static(b"1"), 0, 0).await.unwrap();
+        assert_eq!(&up.get(vec![String::from("k")]).await.unwrap()[0].value[..], b"1");
+
+        let cache = Replicated::new(servers(MockService::new(), up.clone()), 2, 2);
+        assert!(cache.set(String::from("k"), Bytes::from_static(b"2"), 0, 0).await.is_err());
+        assert!(cache.flush_all(0).await.is_err());
+
+        let other = MockService::new().with_fallback(InMemory::new());
+        let cache = Replicated::new(servers(other, up.clone()), 2, 2);
+        cache.set(String::from("k"), Bytes::from_static(b"2"), 0, 0).await.unwrap();
+        cache.flush_all(0).await.unwrap();
+        assert!(up.get(vec![String::from("k")]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn background_writes() {
+        let slow = InMemory::new();
+        let fast = InMemory::new();
+        let cache = Replicated::new(vec![(String::from("a"), MockService::new().with_fallback(Slow(slow.clone()))), (String::from("b"), MockService::new().with_fallback(fast.clone()))], 2, 1);
+        cache.set(String::from("k"), Bytes::from_static(b"1"), 0, 0).await.unwrap();
+        assert_eq!(fast.len(), 1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(slow.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn read_fallback() {
+        let down = MockService::new();
+        let up = MockService::new().with_fallback(InMemory::new());
+        up.set(String::from("k"), Bytes::from_static(b"1"), 0, 0).await.unwrap();
+        let cache = Replicated::new(servers(down, up), 2, 1);
+        let values = cache.get(vec![String::from("k"), String::from("missing")]).await.unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(&values[0].value[..], b"1");
+    }
+}
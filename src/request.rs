@@ -1,17 +1,20 @@
+use bytes::{BufMut, Bytes};
+use std::io;
 use std::str;
 use std::str::FromStr;
-use nom::digit;
+use nom::{digit, IResult};
 
-use ::parse_utils::is_key_char;
+use crate::parse_utils::{is_key_char, payload};
+use crate::key::Key;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Request {
-    Set{key: String, value: Vec<u8>, flags: u16, expiry: u32, noreply: bool},
-    Add{key: String, value: Vec<u8>, flags: u16, expiry: u32, noreply: bool},
-    Replace{key: String, value: Vec<u8>, flags: u16, expiry: u32, noreply: bool},
-    Append{key: String, value: Vec<u8>, noreply: bool},
-    Prepend{key: String, value: Vec<u8>, noreply: bool},
-    Cas{key: String, value: Vec<u8>, flags: u16, expiry: u32, cas: u64, noreply: bool},
+    Set{key: String, value: Bytes, flags: u16, expiry: u32, noreply: bool},
+    Add{key: String, value: Bytes, flags: u16, expiry: u32, noreply: bool},
+    Replace{key: String, value: Bytes, flags: u16, expiry: u32, noreply: bool},
+    Append{key: String, value: Bytes, noreply: bool},
+    Prepend{key: String, value: Bytes, noreply: bool},
+    Cas{key: String, value: Bytes, flags: u16, expiry: u32, cas: u64, noreply: bool},
     Get{keys: Vec<String>},
     Gets{keys: Vec<String>},
     Delete{key: String, noreply: bool},
@@ -23,7 +26,9 @@ pub enum Request {
 }
 
 impl Request {
-    named!(pub parse<&[u8], Request>,
+    // Parses a command line, returning the request and, for storage commands,
+    // the length of the payload that follows.  The payload is left empty.
+    named!(pub parse_header<&[u8], (Request, Option<u32>)>,
         alt!(
             chain!(
                 tag!("set ") ~
@@ -35,10 +40,8 @@ impl Request {
                 tag!(" ") ~
                 len: map_res!(map_res!(digit, str::from_utf8), u32::from_str) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
-                tag!("\r\n") ~
-                value: map!(take!(len), |x: &[u8]| x.to_vec()) ~
                 tag!("\r\n"),
-                || Request::Set{key: key, value: value, flags: flags, expiry: expiry, noreply: noreply}) |
+                || (Request::Set{key: key, value: Bytes::new(), flags: flags, expiry: expiry, noreply: noreply}, Some(len))) |
             chain!(
                 tag!("add ") ~
                 key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())) ~
@@ -49,10 +52,8 @@ impl Request {
                 tag!(" ") ~
                 len: map_res!(map_res!(digit, str::from_utf8), u32::from_str) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
-                tag!("\r\n") ~
-                value: map!(take!(len), |x: &[u8]| x.to_vec()) ~
                 tag!("\r\n"),
-                || Request::Add{key: key, value: value, flags: flags, expiry: expiry, noreply: noreply}) |
+                || (Request::Add{key: key, value: Bytes::new(), flags: flags, expiry: expiry, noreply: noreply}, Some(len))) |
             chain!(
                 tag!("replace ") ~
                 key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())) ~
@@ -63,30 +64,24 @@ impl Request {
                 tag!(" ") ~
                 len: map_res!(map_res!(digit, str::from_utf8), u32::from_str) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
-                tag!("\r\n") ~
-                value: map!(take!(len), |x: &[u8]| x.to_vec()) ~
                 tag!("\r\n"),
-                || Request::Replace{key: key, value: value, flags: flags, expiry: expiry, noreply: noreply}) |
+                || (Request::Replace{key: key, value: Bytes::new(), flags: flags, expiry: expiry, noreply: noreply}, Some(len))) |
             chain!(
                 tag!("append ") ~
                 key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())) ~
                 tag!(" ") ~
                 len: map_res!(map_res!(digit, str::from_utf8), u32::from_str) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
-                tag!("\r\n") ~
-                value: map!(take!(len), |x: &[u8]| x.to_vec()) ~
                 tag!("\r\n"),
-                || Request::Append{key: key, value: value, noreply: noreply}) |
+                || (Request::Append{key: key, value: Bytes::new(), noreply: noreply}, Some(len))) |
             chain!(
                 tag!("prepend ") ~
                 key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())) ~
                 tag!(" ") ~
                 len: map_res!(map_res!(digit, str::from_utf8), u32::from_str) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
-                tag!("\r\n") ~
-                value: map!(take!(len), |x: &[u8]| x.to_vec()) ~
                 tag!("\r\n"),
-                || Request::Prepend{key: key, value: value, noreply: noreply}) |
+                || (Request::Prepend{key: key, value: Bytes::new(), noreply: noreply}, Some(len))) |
             chain!(
                 tag!("cas ") ~
                 key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())) ~
@@ -99,10 +94,8 @@ impl Request {
                 tag!(" ") ~
                 cas: map_res!(map_res!(digit, str::from_utf8), u64::from_str) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
-                tag!("\r\n") ~
-                value: map!(take!(len), |x: &[u8]| x.to_vec()) ~
                 tag!("\r\n"),
-                || Request::Cas{key: key, value: value, flags: flags, expiry: expiry, cas: cas, noreply: noreply}) |
+                || (Request::Cas{key: key, value: Bytes::new(), flags: flags, expiry: expiry, cas: cas, noreply: noreply}, Some(len))) |
             chain!(
                 tag!("get") ~
                 keys: many0!(chain!(
@@ -110,7 +103,7 @@ impl Request {
                     key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())),
                     || key)) ~
                 tag!("\r\n"),
-                || Request::Get{keys: keys}) |
+                || (Request::Get{keys: keys}, None)) |
             chain!(
                 tag!("gets") ~
                 keys: many0!(chain!(
@@ -118,13 +111,13 @@ impl Request {
                     key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())),
                     || key)) ~
                 tag!("\r\n"),
-                || Request::Gets{keys: keys}) |
+                || (Request::Gets{keys: keys}, None)) |
             chain!(
                 tag!("delete ") ~
                 key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
                 tag!("\r\n"),
-                || Request::Delete{key: key, noreply: noreply}) |
+                || (Request::Delete{key: key, noreply: noreply}, None)) |
             chain!(
                 tag!("incr ") ~
                 key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())) ~
@@ -132,7 +125,7 @@ impl Request {
                 value: map_res!(map_res!(digit, str::from_utf8), u64::from_str) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
                 tag!("\r\n"),
-                || Request::Incr{key: key, value: value, noreply: noreply}) |
+                || (Request::Incr{key: key, value: value, noreply: noreply}, None)) |
             chain!(
                 tag!("decr ") ~
                 key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())) ~
@@ -140,7 +133,7 @@ impl Request {
                 value: map_res!(map_res!(digit, str::from_utf8), u64::from_str) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
                 tag!("\r\n"),
-                || Request::Decr{key: key, value: value, noreply: noreply}) |
+                || (Request::Decr{key: key, value: value, noreply: noreply}, None)) |
             chain!(
                 tag!("touch ") ~
                 key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())) ~
@@ -148,7 +141,7 @@ impl Request {
                 expiry: map_res!(map_res!(digit, str::from_utf8), u32::from_str) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
                 tag!("\r\n"),
-                || Request::Touch{key: key, expiry: expiry, noreply: noreply}) |
+                || (Request::Touch{key: key, expiry: expiry, noreply: noreply}, None)) |
             chain!(
                 tag!("flush_all") ~
                 delay: opt!(chain!(
@@ -157,171 +150,291 @@ impl Request {
                    || delay)) ~
                 noreply: map!(opt!(tag!(" noreply")), |x: Option<_>| x.is_some()) ~
                 tag!("\r\n"),
-                || Request::FlushAll{delay: delay, noreply: noreply}) |
-            map!(tag!("version\r\n"), |_| Request::Version)
+                || (Request::FlushAll{delay: delay, noreply: noreply}, None)) |
+            map!(tag!("version\r\n"), |_| (Request::Version, None))
         ));
 
-    pub fn build(&self, buf: &mut Vec<u8>) {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], Request> {
+        match Request::parse_header(input) {
+            IResult::Done(input, (req, Some(len))) => payload(input, len).map(|value| req.with_value(value)),
+            IResult::Done(input, (req, None)) => IResult::Done(input, req),
+            IResult::Error(err) => IResult::Error(err),
+            IResult::Incomplete(needed) => IResult::Incomplete(needed),
+        }
+    }
+
+    // The request with `payload` as its value, if it is a storage command.
+    pub(crate) fn with_value(mut self, payload: Bytes) -> Request {
+        match self {
+            Request::Set{ref mut value, ..} |
+            Request::Add{ref mut value, ..} |
+            Request::Replace{ref mut value, ..} |
+            Request::Append{ref mut value, ..} |
+            Request::Prepend{ref mut value, ..} |
+            Request::Cas{ref mut value, ..} => *value = payload,
+            _ => {},
+        }
+        self
+    }
+
+    // The command's name, as written on the wire.
+    pub fn command(&self) -> &'static str {
+        match *self {
+            Request::Set{..} => "set",
+            Request::Add{..} => "add",
+            Request::Replace{..} => "replace",
+            Request::Append{..} => "append",
+            Request::Prepend{..} => "prepend",
+            Request::Cas{..} => "cas",
+            Request::Get{..} => "get",
+            Request::Gets{..} => "gets",
+            Request::Delete{..} => "delete",
+            Request::Incr{..} => "incr",
+            Request::Decr{..} => "decr",
+            Request::Touch{..} => "touch",
+            Request::FlushAll{..} => "flush_all",
+            Request::Version => "version",
+        }
+    }
+
+    // The key a single-key request operates on.
+    pub fn key(&self) -> Option<&str> {
+        match *self {
+            Request::Set{ref key, ..} |
+            Request::Add{ref key, ..} |
+            Request::Replace{ref key, ..} |
+            Request::Append{ref key, ..} |
+            Request::Prepend{ref key, ..} |
+            Request::Cas{ref key, ..} |
+            Request::Delete{ref key, ..} |
+            Request::Incr{ref key, ..} |
+            Request::Decr{ref key, ..} |
+            Request::Touch{ref key, ..} => Some(key),
+            Request::Get{..} |
+            Request::Gets{..} |
+            Request::FlushAll{..} |
+            Request::Version => None,
+        }
+    }
+
+    // Whether sending this request twice has the same effect as sending it
+    // once, i.e. whether it is safe to resend after a lost response.
+    pub fn is_idempotent(&self) -> bool {
+        match *self {
+            Request::Set{..} |
+            Request::Replace{..} |
+            Request::Get{..} |
+            Request::Gets{..} |
+            Request::Delete{..} |
+            Request::Touch{..} |
+            Request::FlushAll{..} |
+            Request::Version => true,
+            Request::Add{..} |
+            Request::Append{..} |
+            Request::Prepend{..} |
+            Request::Cas{..} |
+            Request::Incr{..} |
+            Request::Decr{..} => false,
+        }
+    }
+
+    // Checks that every key in the request can be sent as-is, so that a bad
+    // key fails this request rather than corrupting the stream.
+    pub fn validate(&self) -> io::Result<()> {
+        match *self {
+            Request::Get{ref keys} |
+            Request::Gets{ref keys} => {
+                if keys.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "no keys given"));
+                }
+                keys.iter().try_for_each(|key| Key::validate(key))
+            },
+            _ => self.key().map_or(Ok(()), Key::validate),
+        }
+    }
+
+    // Rewrites every key in the request.
+    pub fn map_keys<F: FnMut(String) -> String>(self, mut f: F) -> Request {
+        match self {
+            Request::Set{key, value, flags, expiry, noreply} => Request::Set{key: f(key), value: value, flags: flags, expiry: expiry, noreply: noreply},
+            Request::Add{key, value, flags, expiry, noreply} => Request::Add{key: f(key), value: value, flags: flags, expiry: expiry, noreply: noreply},
+            Request::Replace{key, value, flags, expiry, noreply} => Request::Replace{key: f(key), value: value, flags: flags, expiry: expiry, noreply: noreply},
+            Request::Append{key, value, noreply} => Request::Append{key: f(key), value: value, noreply: noreply},
+            Request::Prepend{key, value, noreply} => Request::Prepend{key: f(key), value: value, noreply: noreply},
+            Request::Cas{key, value, flags, expiry, cas, noreply} => Request::Cas{key: f(key), value: value, flags: flags, expiry: expiry, cas: cas, noreply: noreply},
+            Request::Get{keys} => Request::Get{keys: keys.into_iter().map(f).collect()},
+            Request::Gets{keys} => Request::Gets{keys: keys.into_iter().map(f).collect()},
+            Request::Delete{key, noreply} => Request::Delete{key: f(key), noreply: noreply},
+            Request::Incr{key, value, noreply} => Request::Incr{key: f(key), value: value, noreply: noreply},
+            Request::Decr{key, value, noreply} => Request::Decr{key: f(key), value: value, noreply: noreply},
+            Request::Touch{key, expiry, noreply} => Request::Touch{key: f(key), expiry: expiry, noreply: noreply},
+            req => req,
+        }
+    }
+
+    pub fn build<B: BufMut>(&self, buf: &mut B) -> io::Result<()> {
+        self.validate()?;
         match *self {
             Request::Set{ref key, ref value, flags, expiry, noreply} => {
-                buf.extend_from_slice(b"set ");
-                buf.extend_from_slice(key.as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(flags.to_string().as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(expiry.to_string().as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(value.len().to_string().as_bytes());
+                buf.put_slice(b"set ");
+                buf.put_slice(key.as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(flags.to_string().as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(expiry.to_string().as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(value.len().to_string().as_bytes());
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
-                buf.extend_from_slice(value.as_slice());
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
+                buf.put_slice(&value[..]);
+                buf.put_slice(b"\r\n");
             },
             Request::Add{ref key, ref value, flags, expiry, noreply} => {
-                buf.extend_from_slice(b"add ");
-                buf.extend_from_slice(key.as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(flags.to_string().as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(expiry.to_string().as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(value.len().to_string().as_bytes());
+                buf.put_slice(b"add ");
+                buf.put_slice(key.as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(flags.to_string().as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(expiry.to_string().as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(value.len().to_string().as_bytes());
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
-                buf.extend_from_slice(value.as_slice());
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
+                buf.put_slice(&value[..]);
+                buf.put_slice(b"\r\n");
             },
             Request::Replace{ref key, ref value, flags, expiry, noreply} => {
-                buf.extend_from_slice(b"replace ");
-                buf.extend_from_slice(key.as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(flags.to_string().as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(expiry.to_string().as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(value.len().to_string().as_bytes());
+                buf.put_slice(b"replace ");
+                buf.put_slice(key.as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(flags.to_string().as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(expiry.to_string().as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(value.len().to_string().as_bytes());
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
-                buf.extend_from_slice(value.as_slice());
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
+                buf.put_slice(&value[..]);
+                buf.put_slice(b"\r\n");
             },
             Request::Append{ref key, ref value, noreply} => {
-                buf.extend_from_slice(b"append ");
-                buf.extend_from_slice(key.as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(value.len().to_string().as_bytes());
+                buf.put_slice(b"append ");
+                buf.put_slice(key.as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(value.len().to_string().as_bytes());
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
-                buf.extend_from_slice(value.as_slice());
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
+                buf.put_slice(&value[..]);
+                buf.put_slice(b"\r\n");
             },
             Request::Prepend{ref key, ref value, noreply} => {
-                buf.extend_from_slice(b"prepend ");
-                buf.extend_from_slice(key.as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(value.len().to_string().as_bytes());
+                buf.put_slice(b"prepend ");
+                buf.put_slice(key.as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(value.len().to_string().as_bytes());
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
-                buf.extend_from_slice(value.as_slice());
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
+                buf.put_slice(&value[..]);
+                buf.put_slice(b"\r\n");
             },
             Request::Cas{ref key, ref value, flags, expiry, cas, noreply} => {
-                buf.extend_from_slice(b"cas ");
-                buf.extend_from_slice(key.as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(flags.to_string().as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(expiry.to_string().as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(value.len().to_string().as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(cas.to_string().as_bytes());
+                buf.put_slice(b"cas ");
+                buf.put_slice(key.as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(flags.to_string().as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(expiry.to_string().as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(value.len().to_string().as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(cas.to_string().as_bytes());
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
-                buf.extend_from_slice(value.as_slice());
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
+                buf.put_slice(&value[..]);
+                buf.put_slice(b"\r\n");
             },
             Request::Get{ref keys} => {
-                buf.extend_from_slice(b"get");
+                buf.put_slice(b"get");
                 for key in keys.iter() {
-                    buf.extend_from_slice(b" ");
-                    buf.extend_from_slice(key.as_bytes());
+                    buf.put_slice(b" ");
+                    buf.put_slice(key.as_bytes());
                 }
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
             },
             Request::Gets{ref keys} => {
-                buf.extend_from_slice(b"gets");
+                buf.put_slice(b"gets");
                 for key in keys.iter() {
-                    buf.extend_from_slice(b" ");
-                    buf.extend_from_slice(key.as_bytes());
+                    buf.put_slice(b" ");
+                    buf.put_slice(key.as_bytes());
                 }
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
             },
             Request::Delete{ref key, noreply} => {
-                buf.extend_from_slice(b"delete ");
-                buf.extend_from_slice(key.as_bytes());
+                buf.put_slice(b"delete ");
+                buf.put_slice(key.as_bytes());
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
             },
             Request::Incr{ref key, value, noreply} => {
-                buf.extend_from_slice(b"incr ");
-                buf.extend_from_slice(key.as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(value.to_string().as_bytes());
+                buf.put_slice(b"incr ");
+                buf.put_slice(key.as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(value.to_string().as_bytes());
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
             },
             Request::Decr{ref key, value, noreply} => {
-                buf.extend_from_slice(b"decr ");
-                buf.extend_from_slice(key.as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(value.to_string().as_bytes());
+                buf.put_slice(b"decr ");
+                buf.put_slice(key.as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(value.to_string().as_bytes());
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
             },
             Request::Touch{ref key, expiry, noreply} => {
-                buf.extend_from_slice(b"touch ");
-                buf.extend_from_slice(key.as_bytes());
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(expiry.to_string().as_bytes());
+                buf.put_slice(b"touch ");
+                buf.put_slice(key.as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(expiry.to_string().as_bytes());
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
             },
             Request::FlushAll{delay, noreply} => {
-                buf.extend_from_slice(b"flush_all ");
+                buf.put_slice(b"flush_all ");
                 match delay {
                     Some(delay) => {
-                        buf.extend_from_slice(b" ");
-                        buf.extend_from_slice(delay.to_string().as_bytes());
+                        buf.put_slice(b" ");
+                        buf.put_slice(delay.to_string().as_bytes());
                     },
                     None => {}
                 }
                 if noreply {
-                    buf.extend_from_slice(b" noreply");
+                    buf.put_slice(b" noreply");
                 }
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"\r\n");
             },
-            Request::Version => buf.extend_from_slice(b"version\r\n")
+            Request::Version => buf.put_slice(b"version\r\n")
         }
+        Ok(())
     }
 }
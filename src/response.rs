@@ -1,8 +1,11 @@
-use nom::not_line_ending;
+use bytes::BufMut;
+use nom::{digit, not_line_ending};
+use std::str;
+use std::str::FromStr;
 
-use ::value::Value;
+use crate::value::Value;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Response {
     Error,
     ClientError(String),
@@ -34,9 +37,9 @@ impl Response {
                 tag!("\r\n"),
                 || Response::ServerError(message)) |
             map!(tag!("STORED\r\n"), |_| Response::Stored) |
-            map!(tag!("NOT STORED\r\n"), |_| Response::NotStored) |
+            map!(tag!("NOT_STORED\r\n"), |_| Response::NotStored) |
             map!(tag!("EXISTS\r\n"), |_| Response::Exists) |
-            map!(tag!("NOT FOUND\r\n"), |_| Response::NotFound) |
+            map!(tag!("NOT_FOUND\r\n"), |_| Response::NotFound) |
             chain!(
                 values: many0!(Value::parse) ~
                 tag!("END\r\n"),
@@ -48,43 +51,47 @@ impl Response {
                 tag!("VERSION ") ~
                 version: map_res!(not_line_ending, |x: &[u8]| String::from_utf8(x.to_vec())) ~
                 tag!("\r\n"),
-                || Response::Version(version))
+                || Response::Version(version)) |
+            chain!(
+                value: map_res!(map_res!(digit, str::from_utf8), u64::from_str) ~
+                tag!("\r\n"),
+                || Response::UpdatedValue(value))
         ));
 
-    pub fn build(&self, buf: &mut Vec<u8>) {
+    pub fn build<B: BufMut>(&self, buf: &mut B) {
         match *self {
-            Response::Error => buf.extend_from_slice(b"ERROR\r\n"),
+            Response::Error => buf.put_slice(b"ERROR\r\n"),
             Response::ClientError(ref message) => {
-                buf.extend_from_slice(b"CLIENT_ERROR ");
-                buf.extend_from_slice(message.as_bytes());
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"CLIENT_ERROR ");
+                buf.put_slice(message.as_bytes());
+                buf.put_slice(b"\r\n");
             },
             Response::ServerError(ref message) => {
-                buf.extend_from_slice(b"SERVER_ERROR ");
-                buf.extend_from_slice(message.as_bytes());
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"SERVER_ERROR ");
+                buf.put_slice(message.as_bytes());
+                buf.put_slice(b"\r\n");
             },
-            Response::Stored => buf.extend_from_slice(b"STORED\r\n"),
-            Response::NotStored => buf.extend_from_slice(b"NOT STORED\r\n"),
-            Response::Exists => buf.extend_from_slice(b"EXISTS\r\n"),
-            Response::NotFound => buf.extend_from_slice(b"NOT FOUND\r\n"),
+            Response::Stored => buf.put_slice(b"STORED\r\n"),
+            Response::NotStored => buf.put_slice(b"NOT_STORED\r\n"),
+            Response::Exists => buf.put_slice(b"EXISTS\r\n"),
+            Response::NotFound => buf.put_slice(b"NOT_FOUND\r\n"),
             Response::Values(ref values) => {
                 for value in values.iter() {
                     value.build(buf);
                 }
-                buf.extend_from_slice(b"END\r\n");
+                buf.put_slice(b"END\r\n");
             },
-            Response::Deleted => buf.extend_from_slice(b"DELETED\r\n"),
+            Response::Deleted => buf.put_slice(b"DELETED\r\n"),
             Response::UpdatedValue(value) => {
-                buf.extend_from_slice(value.to_string().as_bytes());
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(value.to_string().as_bytes());
+                buf.put_slice(b"\r\n");
             },
-            Response::Touched => buf.extend_from_slice(b"TOUCHED\r\n"),
-            Response::Ok => buf.extend_from_slice(b"OK\r\n"),
+            Response::Touched => buf.put_slice(b"TOUCHED\r\n"),
+            Response::Ok => buf.put_slice(b"OK\r\n"),
             Response::Version(ref version) => {
-                buf.extend_from_slice(b"VERSION ");
-                buf.extend_from_slice(version.as_bytes());
-                buf.extend_from_slice(b"\r\n");
+                buf.put_slice(b"VERSION ");
+                buf.put_slice(version.as_bytes());
+                buf.put_slice(b"\r\n");
             },
         }
     }
@@ -0,0 +1,169 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::backoff::Backoff;
+use crate::service::{Service, BoxFuture};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    backoff: Backoff,
+    retry_non_idempotent: bool,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> RetryPolicy {
+        RetryPolicy{max_retries: max_retries, backoff: Backoff::default(), retry_non_idempotent: false}
+    }
+
+    pub fn with_backoff(self, backoff: Backoff) -> RetryPolicy {
+        RetryPolicy{backoff: backoff, ..self}
+    }
+
+    // Opts in to retrying requests such as `incr` and `append`, which may be
+    // applied twice if the first attempt reached the server.
+    pub fn retry_non_idempotent(self, retry_non_idempotent: bool) -> RetryPolicy {
+        RetryPolicy{retry_non_idempotent: retry_non_idempotent, ..self}
+    }
+
+    pub fn should_retry(&self, req: &Request, err: &io::Error, attempt: u32) -> bool {
+        attempt < self.max_retries &&
+            (self.retry_non_idempotent || req.is_idempotent()) &&
+            is_transient(err)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::new(3)
+    }
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(),
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::NotConnected |
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::UnexpectedEof)
+}
+
+pub struct Retry<S> {
+    inner: Arc<S>,
+    policy: RetryPolicy,
+}
+
+impl<S> Retry<S> {
+    pub fn new(inner: S, policy: RetryPolicy) -> Retry<S> {
+        Retry{inner: Arc::new(inner), policy: policy}
+    }
+}
+
+impl<S> Service for Retry<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
+          S::Future: Send {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let mut attempt = 0;
+            loop {
+                match inner.call(req.clone()).await {
+                    Err(ref err) if policy.should_retry(&req, err, attempt) => {
+                        tokio::time::sleep(policy.backoff.delay(attempt)).await;
+                        attempt += 1;
+                    },
+                    result => return result,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+    use bytes::Bytes;
+    use crate::backoff::Backoff;
+    use crate::mock::{Expectation, MockService};
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::retry::{Retry, RetryPolicy};
+    use crate::service::Service;
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy::new(max_retries).with_backoff(Backoff::new(Duration::from_millis(1), Duration::from_millis(1)))
+    }
+
+    #[test]
+    fn should_retry() {
+        let policy = RetryPolicy::new(2);
+        let get = Request::Get{keys: vec![String::from("key")]};
+        let incr = Request::Incr{key: String::from("key"), value: 1, noreply: false};
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        let invalid = io::Error::new(io::ErrorKind::InvalidData, "invalid");
+        assert!(policy.should_retry(&get, &reset, 0));
+        assert!(policy.should_retry(&get, &reset, 1));
+        assert!(!policy.should_retry(&get, &reset, 2));
+        assert!(!policy.should_retry(&get, &invalid, 0));
+        assert!(!policy.should_retry(&incr, &reset, 0));
+        assert!(policy.retry_non_idempotent(true).should_retry(&incr, &reset, 0));
+    }
+
+    #[tokio::test]
+    async fn retries_idempotent() {
+        let get = Request::Get{keys: vec![String::from("key")]};
+        let mock = MockService::new();
+        mock.expect(Expectation::new(get.clone()).fail(io::ErrorKind::ConnectionReset, "reset"))
+            .expect(Expectation::new(get.clone()).fail(io::ErrorKind::TimedOut, "timed out"))
+            .expect(Expectation::new(get.clone()).respond(Response::Values(Vec::new())));
+        let retry = Retry::new(mock.clone(), policy(2));
+        assert!(matches!(retry.call(get.clone()).await, Ok(Response::Values(ref values)) if values.is_empty()));
+        assert_eq!(mock.calls().len(), 3);
+        mock.verify();
+
+        // Gives up once the limit is reached, with the last error.
+        for _ in 0..4 {
+            mock.expect(Expectation::new(get.clone()).fail(io::ErrorKind::ConnectionReset, "reset"));
+        }
+        let err = retry.call(get.clone()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(mock.calls().len(), 6);
+
+        // Errors that aren't transient are returned at once.
+        let mock = MockService::new();
+        mock.expect(Expectation::new(get.clone()).fail(io::ErrorKind::InvalidData, "invalid"));
+        let err = Retry::new(mock.clone(), policy(2)).call(get).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(mock.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn no_retry_non_idempotent() {
+        let key = String::from("key");
+        let value = Bytes::from_static(b"value");
+        let requests = vec![
+            Request::Incr{key: key.clone(), value: 1, noreply: false},
+            Request::Append{key: key.clone(), value: value.clone(), noreply: false},
+            Request::Cas{key: key, value: value, flags: 0, expiry: 0, cas: 1, noreply: false},
+        ];
+        for req in requests {
+            let mock = MockService::new();
+            mock.expect(Expectation::new(req.clone()).fail(io::ErrorKind::ConnectionReset, "reset"))
+                .expect(Expectation::new(req.clone()).respond(Response::Stored));
+            let err = Retry::new(mock.clone(), policy(2)).call(req.clone()).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            assert_eq!(mock.calls(), vec![req]);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashSet;
+
+use crate::hash;
+
+const POINTS_PER_NODE: usize = 160;
+
+// A consistent hash ring.  Each node is placed on the ring at a number of
+// pseudo-random points derived from its name, and a key belongs to the first
+// node found walking clockwise from the key's own hash.
+#[derive(Debug, Clone)]
+pub struct Ring {
+    nodes: usize,
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    pub fn new<S: AsRef<str>>(names: &[S]) -> Ring {
+        let mut points = Vec::with_capacity(names.len() * POINTS_PER_NODE);
+        for (index, name) in names.iter().enumerate() {
+            for point in 0..POINTS_PER_NODE {
+                points.push((position(format!("{}-{}", name.as_ref(), point).as_bytes()), index));
+            }
+        }
+        points.sort();
+        Ring{nodes: names.len(), points: points}
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes == 0
+    }
+
+    pub fn primary(&self, key: &str) -> Option<usize> {
+        self.nodes(key, 1).first().cloned()
+    }
+
+    // Up to `count` distinct nodes for the key, primary first.
+    pub fn nodes(&self, key: &str, count: usize) -> Vec<usize> {
+        let mut nodes = Vec::with_capacity(count);
+        if self.points.is_empty() {
+            return nodes;
+        }
+        let mut seen = HashSet::new();
+        let start = match self.points.binary_search(&(position(key.as_bytes()), 0)) {
+            Ok(index) | Err(index) => index,
+        };
+        for offset in 0..self.points.len() {
+            if nodes.len() >= count {
+                break;
+            }
+            let (_, node) = self.points[(start + offset) % self.points.len()];
+            if seen.insert(node) {
+                nodes.push(node);
+            }
+        }
+        nodes
+    }
+}
+
+fn position(bytes: &[u8]) -> u64 {
+    hash::mix(hash::fnv1a(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ring::Ring;
+
+    #[test]
+    fn nodes() {
+        let ring = Ring::new(&["a:11211", "b:11211", "c:11211"]);
+        for key in &["alpha", "beta", "gamma", "delta"] {
+            let nodes = ring.nodes(key, 5);
+            assert_eq!(3, nodes.len());
+            assert_eq!(Some(nodes[0]), ring.primary(key));
+            assert_eq!(nodes[..2], ring.nodes(key, 2)[..]);
+        }
+    }
+
+    #[test]
+    fn stable_when_node_added() {
+        let before = Ring::new(&["a:11211", "b:11211", "c:11211"]);
+        let after = Ring::new(&["a:11211", "b:11211", "c:11211", "d:11211"]);
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        let moved = keys.iter().filter(|key| before.primary(key) != after.primary(key)).count();
+        assert!(moved < 400, "{} of {} keys moved", moved, keys.len());
+        assert!(keys.iter().all(|key| {
+            before.primary(key) == after.primary(key) || after.primary(key) == Some(3)
+        }));
+    }
+}
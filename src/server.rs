@@ -1,171 +1,615 @@
-use futures::{Future, future};
-use tokio_proto::TcpServer;
-use tokio_service::{Service, NewService};
+use futures::{future, stream};
+use futures::stream::FuturesOrdered;
+use futures::{FutureExt, SinkExt, StreamExt, TryStreamExt};
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_util::codec::Framed;
 use std::io;
+use std::marker::PhantomData;
 use std::net::SocketAddr;
-use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub use request::Request;
-pub use response::Response;
-pub use value::Value;
-pub use proto::Proto;
-pub use api::Api;
+use crate::request::Request;
+use crate::response::Response;
+use crate::proto::{ServerCodec, ValueFrame, MAX_VALUE_LEN};
+use crate::api::Api;
+use crate::service::{Service, NewService, BoxFuture};
+use crate::stream::{ValueStream, ValueStreams};
+use crate::metrics::{Counted, Metrics};
 
-pub struct ApiService<T> {
+// How a backend error is reported to the client: as a response such as
+// `SERVER_ERROR`, `CLIENT_ERROR` or `NOT_FOUND`, or, if the error leaves the
+// connection unusable, as an error that closes it.
+pub trait IntoResponse {
+    fn into_response(self) -> io::Result<Response>;
+}
+
+// Reported as `Client` and `InMemory` report responses as errors: `NotFound`
+// as `NOT_FOUND`, `AlreadyExists` as `EXISTS` and `InvalidInput` as
+// `CLIENT_ERROR`, so a cache served through `ApiService` answers as it was
+// answered.  Anything else is a `SERVER_ERROR` with the error's message.
+impl IntoResponse for io::Error {
+    fn into_response(self) -> io::Result<Response> {
+        Ok(match self.kind() {
+            io::ErrorKind::NotFound => Response::NotFound,
+            io::ErrorKind::AlreadyExists => Response::Exists,
+            io::ErrorKind::InvalidInput => Response::ClientError(self.to_string()),
+            _ => Response::ServerError(self.to_string()),
+        })
+    }
+}
+
+// memcached answers `NOT_STORED` when `add`, `replace`, `append` or `prepend`
+// finds the key present or missing, which `Api` reports as `AlreadyExists` or
+// `NotFound`.
+fn not_stored(result: io::Result<Response>) -> io::Result<Response> {
+    match result {
+        Ok(Response::NotFound) | Ok(Response::Exists) => Ok(Response::NotStored),
+        result => result,
+    }
+}
+
+// Serves an `Api`, answering its errors as their `IntoResponse` says.
+pub struct ApiService<T, E = io::Error> {
     api: T,
+    error: PhantomData<fn() -> E>,
 }
 
-impl<T> ApiService<T> {
-    pub fn new(api: T) -> ApiService<T> {
-        ApiService{api: api}
+impl<T, E> ApiService<T, E> {
+    pub fn new(api: T) -> ApiService<T, E> {
+        ApiService{api: api, error: PhantomData}
     }
 }
 
-impl<T> Service for ApiService<T>
-    where T: Api<io::Error>, // TODO: Genericize this over non-io errors.
-          T::FutureUnit: Send + 'static,
-          T::FutureValues: Send + 'static,
-          T::FutureU64: Send + 'static,
-          T::FutureString: Send + 'static {
+impl<T, E> Service for ApiService<T, E>
+    where T: Api<E>,
+          E: IntoResponse {
     type Request = Request;
     type Response = Response;
     type Error = io::Error;
-    type Future = Box<Future<Item = Response, Error = io::Error>>;
+    type Future = BoxFuture<Response>;
 
     fn call(&self, req: Request) -> Self::Future {
         match req {
             Request::Set{key, value, flags, expiry, noreply: _} => {
                 self.api.set(key, value, flags, expiry)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(()) => Ok(Response::Stored),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => err.into_response(),
+                        }
                     }).boxed()
             },
             Request::Add{key, value, flags, expiry, noreply: _} => {
                 self.api.add(key, value, flags, expiry)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(()) => Ok(Response::Stored),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => not_stored(err.into_response()),
+                        }
                     }).boxed()
             },
             Request::Replace{key, value, flags, expiry, noreply: _} => {
                 self.api.replace(key, value, flags, expiry)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(()) => Ok(Response::Stored),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => not_stored(err.into_response()),
+                        }
                     }).boxed()
             },
             Request::Append{key, value, noreply: _} => {
                 self.api.append(key, value)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(()) => Ok(Response::Stored),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => not_stored(err.into_response()),
+                        }
                     }).boxed()
             },
             Request::Prepend{key, value, noreply: _} => {
                 self.api.prepend(key, value)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(()) => Ok(Response::Stored),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => not_stored(err.into_response()),
+                        }
                     }).boxed()
             },
             Request::Cas{key, value, flags, expiry, cas, noreply: _} => {
                 self.api.cas(key, value, flags, expiry, cas)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(()) => Ok(Response::Stored),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => err.into_response(),
+                        }
                     }).boxed()
             },
             Request::Get{keys} => {
                 self.api.get(keys)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(values) => Ok(Response::Values(values)),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => err.into_response(),
+                        }
                     }).boxed()
             },
             Request::Gets{keys} => {
                 self.api.gets(keys)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(values) => Ok(Response::Values(values)),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => err.into_response(),
+                        }
                     }).boxed()
             },
             Request::Delete{key, noreply: _} => {
                 self.api.delete(key)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(()) => Ok(Response::Deleted),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => err.into_response(),
+                        }
                     }).boxed()
             },
             Request::Incr{key, value, noreply: _} => {
                 self.api.incr(key, value)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(value) => Ok(Response::UpdatedValue(value)),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => err.into_response(),
+                        }
                     }).boxed()
             },
             Request::Decr{key, value, noreply: _} => {
                 self.api.decr(key, value)
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(value) => Ok(Response::UpdatedValue(value)),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => err.into_response(),
+                        }
                     }).boxed()
             },
             Request::Touch{key, expiry, noreply: _} => {
                 self.api.touch(key, expiry)
-                    .then(|result| {
-                        future::done(match result {
-                            Ok(()) => Ok(Response::Stored),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                    .map(|result| {
+                        match result {
+                            Ok(()) => Ok(Response::Touched),
+                            Err(err) => err.into_response(),
+                        }
                     }).boxed()
             },
             Request::FlushAll{delay, noreply: _} => {
                 self.api.flush_all(delay.unwrap_or(0))
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(()) => Ok(Response::Ok),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => err.into_response(),
+                        }
                     }).boxed()
             },
             Request::Version => {
                 self.api.version()
-                    .then(|result| {
-                        future::done(match result {
+                    .map(|result| {
+                        match result {
                             Ok(version) => Ok(Response::Version(version)),
-                            Err(err) => Ok(Response::ServerError(String::from(err.description()))),
-                        })
+                            Err(err) => err.into_response(),
+                        }
                     }).boxed()
             },
         }
     }
 }
 
-pub fn serve<T>(addr: SocketAddr, new_service: T)
-    where T: NewService<Request = Request, Response = Response, Error = io::Error> + Send + Sync + 'static,
-{
-    TcpServer::new(Proto, addr).serve(new_service);
+// How many `get_stream` lookups the default `get_many_stream` runs at once.
+const GET_AHEAD: usize = 16;
+
+// What a server writes in reply to a request.  Streamed values are written
+// as they and their bodies are produced, so they are never held in memory
+// whole.
+pub enum Reply {
+    Response(Response),
+    Values(ValueStreams),
+}
+
+impl From<Response> for Reply {
+    fn from(rsp: Response) -> Reply {
+        Reply::Response(rsp)
+    }
+}
+
+// An `Api` that can stream out the bodies of values too large to hold in
+// memory.
+pub trait StreamingApi: Api<io::Error> {
+    // The value of `key` with its body as a stream, or `None` on a miss.
+    fn get_stream(&self, key: String) -> BoxFuture<Option<ValueStream>>;
+
+    // The values found for `keys`, in order, each yielded as soon as it is
+    // ready.  By default this looks up several keys at once with
+    // `get_stream`.
+    fn get_many_stream(&self, keys: Vec<String>) -> ValueStreams {
+        let values: Vec<_> = keys.into_iter().map(|key| self.get_stream(key)).collect();
+        Box::pin(stream::iter(values).buffered(GET_AHEAD).try_filter_map(future::ok))
+    }
+}
+
+// Serves a `StreamingApi`, streaming the values of `get` and `gets` from
+// `get_many_stream` and answering other requests as `ApiService` does.
+pub struct StreamingApiService<T> {
+    inner: ApiService<T>,
+}
+
+impl<T> StreamingApiService<T> {
+    pub fn new(api: T) -> StreamingApiService<T> {
+        StreamingApiService{inner: ApiService::new(api)}
+    }
+}
+
+impl<T> StreamingApiService<T>
+    where T: StreamingApi {
+    fn values(&self, keys: Vec<String>, cas: bool) -> BoxFuture<Reply> {
+        let values = self.inner.api.get_many_stream(keys).map_ok(move |mut value| {
+            if !cas {
+                value.cas = None;
+            }
+            value
+        });
+        future::ok(Reply::Values(Box::pin(values))).boxed()
+    }
+}
+
+impl<T> Service for StreamingApiService<T>
+    where T: StreamingApi {
+    type Request = Request;
+    type Response = Reply;
+    type Error = io::Error;
+    type Future = BoxFuture<Reply>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        match req {
+            Request::Get{keys} => self.values(keys, false),
+            Request::Gets{keys} => self.values(keys, true),
+            req => self.inner.call(req).map(|rsp| rsp.map(Reply::Response)).boxed(),
+        }
+    }
+}
+
+// How many requests on a connection `serve` works on at once by default.
+const MAX_IN_FLIGHT: usize = 32;
+
+// How long to stop accepting after a failed accept, e.g. because the process
+// is out of file descriptors, rather than retrying straight away.
+pub(crate) const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Accepts connections, answering each with a service from `new_service`.
+// Requests on a connection are dispatched as they are read, up to a limit,
+// and their responses written back in request order, so a slow request only
+// holds up the responses behind it rather than the work on them.
+pub struct Server<T> {
+    new_service: T,
+    max_in_flight: usize,
+    max_value_len: usize,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl<T> Server<T>
+    where T: NewService<Request = Request, Error = io::Error> + Send + Sync + 'static,
+          T::Response: Into<Reply> + Send,
+          T::Instance: Send + 'static,
+          <T::Instance as Service>::Future: Send {
+    pub fn new(new_service: T) -> Server<T> {
+        Server{new_service: new_service, max_in_flight: MAX_IN_FLIGHT, max_value_len: MAX_VALUE_LEN, metrics: None}
+    }
+
+    // Sets how many requests on a connection may be worked on at once, and so
+    // how many finished responses may wait on an earlier one.  Reading from
+    // a connection stops while it is at the limit.  1 answers requests one
+    // at a time.
+    pub fn with_max_in_flight(self, max_in_flight: usize) -> Server<T> {
+        Server{max_in_flight: max_in_flight.max(1), ..self}
+    }
+
+    // Sets the largest value a client may store.  A longer one is refused
+    // before it is read, and closes the connection.
+    pub fn with_max_value_len(self, max_value_len: usize) -> Server<T> {
+        Server{max_value_len: max_value_len, ..self}
+    }
+
+    // Counts connections and the bytes through them in `metrics`.
+    // Per-command counts come from wrapping each service in a
+    // `MetricsLayer`.
+    pub fn with_metrics(self, metrics: Arc<Metrics>) -> Server<T> {
+        Server{metrics: Some(metrics), ..self}
+    }
+
+    // Only fails if `addr` can't be bound.  Errors accepting a connection or
+    // creating its service are logged, and close just that connection.
+    pub async fn serve(self, addr: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("accept on {}: {}", addr, err);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                },
+            };
+            let _ = stream.set_nodelay(true);
+            let service = match self.new_service.new_service() {
+                Ok(service) => service,
+                Err(err) => {
+                    warn!("creating a service for {}: {}", peer, err);
+                    continue;
+                },
+            };
+            let codec = ServerCodec::new().with_max_value_len(self.max_value_len);
+            match self.metrics {
+                Some(ref metrics) => tokio::spawn(connection(Framed::new(Counted::new(stream, metrics.clone()), codec), service, self.max_in_flight)),
+                None => tokio::spawn(connection(Framed::new(stream, codec), service, self.max_in_flight)),
+            };
+        }
+    }
+}
+
+// Accepts connections on `addr`, answering each with a service from
+// `new_service`, as `Server` does with its defaults.
+pub async fn serve<T>(addr: SocketAddr, new_service: T) -> io::Result<()>
+    where T: NewService<Request = Request, Error = io::Error> + Send + Sync + 'static,
+          T::Response: Into<Reply> + Send,
+          T::Instance: Send + 'static,
+          <T::Instance as Service>::Future: Send {
+    Server::new(new_service).serve(addr).await
+}
+
+async fn connection<S, T>(mut transport: Framed<T, ServerCodec>, service: S, max_in_flight: usize)
+    where S: Service<Request = Request, Error = io::Error>,
+          S::Response: Into<Reply>,
+          T: AsyncRead + AsyncWrite + Unpin {
+    let mut in_flight = FuturesOrdered::new();
+    let mut reading = true;
+    loop {
+        tokio::select! {
+            req = transport.next(), if reading && in_flight.len() < max_in_flight => {
+                match req {
+                    Some(Ok(req)) => in_flight.push_back(service.call(req)),
+                    _ => reading = false,
+                }
+            },
+            reply = in_flight.next(), if !in_flight.is_empty() => {
+                // A failed request, e.g. one timed out by a layer, is
+                // answered on its own; other requests on the connection
+                // carry on.
+                let reply = match reply {
+                    Some(Ok(reply)) => reply.into(),
+                    Some(Err(err)) => match err.into_response() {
+                        Ok(rsp) => Reply::Response(rsp),
+                        Err(_) => break,
+                    },
+                    None => break,
+                };
+                if write(&mut transport, reply).await.is_err() {
+                    break;
+                }
+            },
+            else => break,
+        }
+    }
+}
+
+// Writes a reply, streaming out values and their bodies as they come.  A
+// lookup that fails before any value is written is answered with a
+// `SERVER_ERROR`; any later failure, or a body that doesn't match its length,
+// leaves the connection unusable, so the error closes it.
+async fn write<T>(transport: &mut Framed<T, ServerCodec>, reply: Reply) -> io::Result<()>
+    where T: AsyncRead + AsyncWrite + Unpin {
+    let mut values = match reply {
+        Reply::Response(rsp) => return transport.send(rsp).await,
+        Reply::Values(values) => values,
+    };
+    let mut written = false;
+    while let Some(value) = values.next().await {
+        let mut value = match value {
+            Ok(value) => value,
+            Err(err) if !written => return transport.send(Response::ServerError(err.to_string())).await,
+            Err(err) => return Err(err),
+        };
+        written = true;
+        transport.feed(ValueFrame::Header{key: value.key.clone(), flags: value.flags, len: value.len(), cas: value.cas}).await?;
+        while let Some(chunk) = value.next().await {
+            transport.feed(ValueFrame::Chunk(chunk?)).await?;
+        }
+        transport.feed(ValueFrame::End).await?;
+    }
+    transport.send(Response::Values(Vec::new())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::FutureExt;
+    use futures::future::{self, Ready};
+    use tokio::sync::Notify;
+    use std::io;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use crate::api::ApiHelper;
+    use crate::client::Client;
+    use crate::layer::ServiceBuilder;
+    use crate::memory::InMemory;
+    use crate::mock::{Expectation, MockService};
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::api::Api;
+    use crate::server::{ApiService, IntoResponse, Server};
+    use crate::service::{Service, BoxFuture};
+    use crate::timeout::TimeoutLayer;
+    use crate::value::Value;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Fault {
+        Missing,
+        BadInput,
+        Fatal,
+    }
+
+    impl IntoResponse for Fault {
+        fn into_response(self) -> io::Result<Response> {
+            match self {
+                Fault::Missing => Ok(Response::NotFound),
+                Fault::BadInput => Ok(Response::ClientError(String::from("bad input"))),
+                Fault::Fatal => Err(io::Error::other("fatal")),
+            }
+        }
+    }
+
+    // Fails every request with the fault named by its key.
+    struct Faulty;
+
+    impl Faulty {
+        fn fault<T>(key: &str) -> Ready<Result<T, Fault>> {
+            future::err(match key {
+                "missing" => Fault::Missing,
+                "bad" => Fault::BadInput,
+                _ => Fault::Fatal,
+            })
+        }
+    }
+
+    #[allow(unused_variables)]
+    impl Api<Fault> for Faulty {
+        type FutureUnit = Ready<Result<(), Fault>>;
+        type FutureValues = Ready<Result<Vec<Value>, Fault>>;
+        type FutureU64 = Ready<Result<u64, Fault>>;
+        type FutureString = Ready<Result<String, Fault>>;
+
+        fn set(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit { Faulty::fault(&key) }
+        fn add(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit { Faulty::fault(&key) }
+        fn replace(&self, key: String, value: Bytes, flags: u16, expiry: u32) -> Self::FutureUnit { Faulty::fault(&key) }
+        fn append(&self, key: String, value: Bytes) -> Self::FutureUnit { Faulty::fault(&key) }
+        fn prepend(&self, key: String, value: Bytes) -> Self::FutureUnit { Faulty::fault(&key) }
+        fn cas(&self, key: String, value: Bytes, flags: u16, expiry: u32, cas: u64) -> Self::FutureUnit { Faulty::fault(&key) }
+        fn get(&self, keys: Vec<String>) -> Self::FutureValues { Faulty::fault(&keys[0]) }
+        fn gets(&self, keys: Vec<String>) -> Self::FutureValues { Faulty::fault(&keys[0]) }
+        fn delete(&self, key: String) -> Self::FutureUnit { Faulty::fault(&key) }
+        fn incr(&self, key: String, value: u64) -> Self::FutureU64 { Faulty::fault(&key) }
+        fn decr(&self, key: String, value: u64) -> Self::FutureU64 { Faulty::fault(&key) }
+        fn touch(&self, key: String, expiry: u32) -> Self::FutureUnit { Faulty::fault(&key) }
+        fn flush_all(&self, delay: u32) -> Self::FutureUnit { Faulty::fault("") }
+        fn version(&self) -> Self::FutureString { Faulty::fault("") }
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let service: ApiService<Faulty, Fault> = ApiService::new(Faulty);
+        assert_eq!(service.call(Request::Delete{key: String::from("missing"), noreply: false}).await.unwrap(), Response::NotFound);
+        assert_eq!(service.call(Request::Incr{key: String::from("bad"), value: 1, noreply: false}).await.unwrap(), Response::ClientError(String::from("bad input")));
+        assert!(service.call(Request::Version).await.is_err());
+        let service = ApiService::new(InMemory::new());
+        assert_eq!(service.call(Request::Incr{key: String::from("a"), value: 1, noreply: false}).await.unwrap(), Response::NotFound);
+        assert_eq!(service.call(Request::Append{key: String::from("a"), value: Bytes::from_static(b"1"), noreply: false}).await.unwrap(), Response::NotStored);
+    }
+
+    // Answers a get for "a" only once a get for "b" has been dispatched.
+    struct Gated(Arc<Notify>);
+
+    impl Service for Gated {
+        type Request = Request;
+        type Response = Response;
+        type Error = std::io::Error;
+        type Future = BoxFuture<Response>;
+
+        fn call(&self, req: Request) -> BoxFuture<Response> {
+            let key = match req {
+                Request::Get{mut keys} => keys.remove(0),
+                _ => return futures::future::ok(Response::Error).boxed(),
+            };
+            let gate = self.0.clone();
+            Box::pin(async move {
+                if key == "a" {
+                    gate.notified().await;
+                } else {
+                    gate.notify_one();
+                }
+                Ok(Response::Values(vec![Value{key: key.clone(), value: Bytes::from(key), flags: 0, cas: None}]))
+            })
+        }
+    }
+
+    async fn connect(addr: &SocketAddr) -> Client {
+        let mut attempts = 0;
+        loop {
+            match Client::connect(addr).await {
+                Ok(client) => return client,
+                Err(_) if attempts < 50 => tokio::time::sleep(Duration::from_millis(10)).await,
+                Err(err) => panic!("{}", err),
+            }
+            attempts += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(Server::new(|| Ok(ApiService::new(InMemory::new()))).serve(addr));
+        let client = connect(&addr).await;
+        client.set(String::from("a"), Bytes::from_static(b"1"), 0, 0).await.unwrap();
+        client.touch(String::from("a"), 60).await.unwrap();
+        assert_eq!(client.touch(String::from("b"), 60).await.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(client.add(String::from("a"), Bytes::from_static(b"2"), 0, 0).await.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(client.replace(String::from("b"), Bytes::from_static(b"2"), 0, 0).await.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(client.cas(String::from("a"), Bytes::from_static(b"2"), 0, 0, 12345).await.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn failed_request() {
+        let addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mock = MockService::new().with_fallback(InMemory::new());
+        mock.expect(Expectation::new(Request::Get{keys: vec![String::from("slow")]}).respond(Response::Values(Vec::new())).after(Duration::from_millis(200)));
+        let layers = ServiceBuilder::new().layer(TimeoutLayer::new(Duration::from_millis(50)));
+        tokio::spawn(Server::new(move || Ok(layers.service(mock.clone()))).serve(addr));
+        let client = connect(&addr).await;
+        client.set(String::from("a"), Bytes::from_static(b"1"), 0, 0).await.unwrap();
+        let (slow, fast) = future::join(client.get_one(String::from("slow")), client.get_one(String::from("a"))).await;
+        let err = slow.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(err.to_string().contains("timed out"));
+        assert_eq!(&fast.unwrap().value[..], b"1");
+        assert!(client.version().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_service() {
+        let addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let new_service = {
+            let attempts = attempts.clone();
+            move || match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(io::Error::new(io::ErrorKind::Other, "no backend")),
+                _ => Ok(ApiService::new(InMemory::new())),
+            }
+        };
+        let server = tokio::spawn(Server::new(new_service).serve(addr));
+        let first = connect(&addr).await;
+        assert!(first.version().await.is_err());
+        let second = connect(&addr).await;
+        second.set(String::from("a"), Bytes::from_static(b"1"), 0, 0).await.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(!server.is_finished());
+    }
+
+    #[tokio::test]
+    async fn out_of_order() {
+        let addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(Server::new(|| Ok(Gated(Arc::new(Notify::new())))).with_max_in_flight(2).serve(addr));
+        let client = connect(&addr).await;
+        let both = futures::future::join(client.get_one(String::from("a")), client.get_one(String::from("b")));
+        let (a, b) = tokio::time::timeout(Duration::from_secs(5), both).await.expect("requests were answered one at a time");
+        assert_eq!(&a.unwrap().value[..], b"a");
+        assert_eq!(&b.unwrap().value[..], b"b");
+    }
 }
@@ -0,0 +1,77 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+// A boxed future resolving to an `io::Result`, as returned by most services
+// and helpers in this crate.
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'static>>;
+
+// An asynchronous function from a request to a response.  Every client, layer
+// and server in this crate is a `Service` over `Request` and `Response`, so
+// they can be stacked in any order, and any such service gets `Api` for free.
+pub trait Service {
+    type Request;
+    type Response;
+    type Error;
+    type Future: Future<Output = Result<Self::Response, Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future;
+}
+
+// Creates a `Service` for each connection accepted by `serve`.
+pub trait NewService {
+    type Request;
+    type Response;
+    type Error;
+    type Instance: Service<Request = Self::Request, Response = Self::Response, Error = Self::Error>;
+
+    fn new_service(&self) -> io::Result<Self::Instance>;
+}
+
+impl<F, S> NewService for F
+    where F: Fn() -> io::Result<S>,
+          S: Service {
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Instance = S;
+
+    fn new_service(&self) -> io::Result<S> {
+        self()
+    }
+}
+
+impl<S: Service + ?Sized> Service for Box<S> {
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn call(&self, req: S::Request) -> S::Future {
+        (**self).call(req)
+    }
+}
+
+impl<S: Service + ?Sized> Service for Rc<S> {
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn call(&self, req: S::Request) -> S::Future {
+        (**self).call(req)
+    }
+}
+
+impl<S: Service + ?Sized> Service for Arc<S> {
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn call(&self, req: S::Request) -> S::Future {
+        (**self).call(req)
+    }
+}
@@ -0,0 +1,153 @@
+use bytes::Bytes;
+use futures::{future, stream, Stream};
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::value::Value;
+
+pub type BodyStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + 'static>>;
+
+pub type ValueStreams = Pin<Box<dyn Stream<Item = io::Result<ValueStream>> + Send + 'static>>;
+
+// A value whose body is produced in chunks rather than held in memory whole,
+// as read by `Client::get_stream` or written out by a server from
+// `StreamingApi::get_stream`.  The body must add up to `len` bytes; one that
+// ends early or runs over yields an error instead.
+pub struct ValueStream {
+    pub(crate) key: String,
+    pub(crate) flags: u16,
+    pub(crate) cas: Option<u64>,
+    len: usize,
+    remaining: usize,
+    body: BodyStream,
+}
+
+impl ValueStream {
+    pub fn new<S>(key: String, flags: u16, cas: Option<u64>, len: usize, body: S) -> ValueStream
+        where S: Stream<Item = io::Result<Bytes>> + Send + 'static {
+        ValueStream{key: key, flags: flags, cas: cas, len: len, remaining: len, body: Box::pin(body)}
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    pub fn cas(&self) -> Option<u64> {
+        self.cas
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl From<Value> for ValueStream {
+    fn from(value: Value) -> ValueStream {
+        let len = value.value.len();
+        ValueStream::new(value.key, value.flags, value.cas, len, stream::once(future::ok(value.value)))
+    }
+}
+
+impl Stream for ValueStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Bytes>>> {
+        let this = self.get_mut();
+        match this.body.as_mut().poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if chunk.len() > this.remaining {
+                    this.remaining = 0;
+                    return Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is longer than {} bytes", this.key, this.len)))));
+                }
+                this.remaining -= chunk.len();
+                Poll::Ready(Some(Ok(chunk)))
+            },
+            Poll::Ready(None) if this.remaining > 0 => {
+                this.remaining = 0;
+                Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} ended early", this.key)))))
+            },
+            poll => poll,
+        }
+    }
+}
+
+impl fmt::Debug for ValueStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ValueStream")
+            .field("key", &self.key)
+            .field("flags", &self.flags)
+            .field("cas", &self.cas)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{stream, StreamExt, TryStreamExt};
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Duration;
+    use crate::api::{Api, ApiHelper};
+    use crate::client::Client;
+    use crate::memory::InMemory;
+    use crate::server::{serve, StreamingApi, StreamingApiService};
+    use crate::service::BoxFuture;
+    use crate::stream::ValueStream;
+
+    impl StreamingApi for InMemory {
+        fn get_stream(&self, key: String) -> BoxFuture<Option<ValueStream>> {
+            let value = self.gets(vec![key]);
+            Box::pin(async move {
+                Ok(value.await?.into_iter().next().map(|value| {
+                    let chunks: Vec<_> = value.value.chunks(3).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+                    ValueStream::new(value.key, value.flags, value.cas, value.value.len(), stream::iter(chunks))
+                }))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(serve(addr, || Ok(StreamingApiService::new(InMemory::new()))));
+        let mut attempts = 0;
+        let client = loop {
+            match Client::connect(&addr).await {
+                Ok(client) => break client,
+                Err(_) if attempts < 50 => tokio::time::sleep(Duration::from_millis(10)).await,
+                Err(err) => panic!("{}", err),
+            }
+            attempts += 1;
+        };
+        client.set(String::from("a"), Bytes::from_static(b"streamed value"), 5, 0).await.unwrap();
+        let value = client.get_stream(String::from("a")).await.unwrap().unwrap();
+        assert_eq!((value.key(), value.flags(), value.len(), value.cas()), ("a", 5, 14, None));
+        let chunks: Vec<Bytes> = value.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"streamed value");
+        assert!(client.get_stream(String::from("b")).await.unwrap().is_none());
+        assert_eq!(&client.get_one(String::from("a")).await.unwrap().value[..], b"streamed value");
+
+        client.set(String::from("c"), Bytes::from_static(b"another"), 0, 0).await.unwrap();
+        let keys = vec![String::from("a"), String::from("b"), String::from("c")];
+        let values: Vec<_> = client.get_many_stream(keys).try_collect().await.unwrap();
+        assert_eq!(values.iter().map(|value| (&value.key[..], &value.value[..])).collect::<Vec<_>>(),
+                   vec![("a", &b"streamed value"[..]), ("c", &b"another"[..])]);
+        let invalid: Vec<_> = client.get_many_stream(vec![String::from("bad key")]).collect().await;
+        assert!(invalid[0].is_err());
+
+        let truncated = ValueStream::new(String::from("d"), 0, None, 4, stream::iter(vec![Ok(Bytes::from_static(b"abc"))]));
+        let results: Vec<_> = truncated.collect().await;
+        assert_eq!(results[1].as_ref().unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,60 @@
+use std::io;
+use std::time::Duration;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::layer::Layer;
+use crate::service::{Service, BoxFuture};
+
+// Fails a request with `TimedOut` if it isn't answered within `duration`.
+// Timing starts when the request is made, so it includes any time spent
+// waiting on the layers inside this one.
+pub struct Timeout<S> {
+    inner: S,
+    duration: Duration,
+}
+
+impl<S> Timeout<S> {
+    pub fn new(inner: S, duration: Duration) -> Timeout<S> {
+        Timeout{inner: inner, duration: duration}
+    }
+}
+
+impl<S> Service for Timeout<S>
+    where S: Service<Request = Request, Response = Response, Error = io::Error>,
+          S::Future: Send + 'static {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<Response>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let command = req.command();
+        let rsp = tokio::time::timeout(self.duration, self.inner.call(req));
+        Box::pin(async move {
+            match rsp.await {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", command))),
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutLayer {
+    duration: Duration,
+}
+
+impl TimeoutLayer {
+    pub fn new(duration: Duration) -> TimeoutLayer {
+        TimeoutLayer{duration: duration}
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = Timeout<S>;
+
+    fn layer(&self, inner: S) -> Timeout<S> {
+        Timeout::new(inner, self.duration)
+    }
+}
@@ -0,0 +1,141 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::io;
+
+use crate::value::Value;
+use crate::api::Api;
+use crate::flags;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[cfg(feature = "json")]
+    Json,
+    #[cfg(feature = "bincode")]
+    Bincode,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl Format {
+    pub fn flags(&self) -> u16 {
+        match *self {
+            #[cfg(feature = "json")]
+            Format::Json => flags::TYPE_JSON,
+            #[cfg(feature = "bincode")]
+            Format::Bincode => flags::TYPE_BINCODE,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => flags::TYPE_MSGPACK,
+        }
+    }
+
+    pub fn from_flags(flags: u16) -> Option<Format> {
+        match flags & flags::TYPE_MASK {
+            #[cfg(feature = "json")]
+            flags::TYPE_JSON => Some(Format::Json),
+            #[cfg(feature = "bincode")]
+            flags::TYPE_BINCODE => Some(Format::Bincode),
+            #[cfg(feature = "msgpack")]
+            flags::TYPE_MSGPACK => Some(Format::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> io::Result<Vec<u8>> {
+        // Unused when no format is enabled.
+        let _ = value;
+        match *self {
+            #[cfg(feature = "json")]
+            Format::Json => ::serde_json::to_vec(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => ::bincode::serialize(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => ::rmp_serde::to_vec(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, value: &[u8]) -> io::Result<T> {
+        // Unused when no format is enabled.
+        let _ = value;
+        match *self {
+            #[cfg(feature = "json")]
+            Format::Json => ::serde_json::from_slice(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => ::bincode::deserialize(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => ::rmp_serde::from_slice(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        }
+    }
+
+    // Decodes a value fetched from the cache, refusing values that were
+    // stored in a different format.
+    pub fn decode_value<T: DeserializeOwned>(&self, value: &Value) -> io::Result<T> {
+        match Format::from_flags(value.flags) {
+            Some(format) if format == *self => self.decode(&value.value),
+            Some(format) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is encoded as {:?}, not {:?}", value.key, format, self))),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} has unknown encoding (flags {})", value.key, value.flags))),
+        }
+    }
+}
+
+pub struct TypedCache<A> {
+    api: A,
+    format: Format,
+}
+
+impl<A> TypedCache<A> {
+    pub fn new(api: A, format: Format) -> TypedCache<A> {
+        TypedCache{api: api, format: format}
+    }
+
+    pub fn api(&self) -> &A {
+        &self.api
+    }
+}
+
+impl<A: Api<io::Error>> TypedCache<A> {
+    pub async fn get_as<T: DeserializeOwned>(&self, key: String) -> io::Result<Option<T>> {
+        let values = self.api.get(vec![key]).await?;
+        match values.first() {
+            Some(value) => self.format.decode_value(value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_as<T: Serialize>(&self, key: String, value: &T, expiry: u32) -> io::Result<()> {
+        let value = self.format.encode(value)?;
+        self.api.set(key, value.into(), self.format.flags(), expiry).await
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use crate::api::Api;
+    use crate::memory::InMemory;
+    use crate::value::Value;
+    use crate::typed::{Format, TypedCache};
+
+    #[test]
+    fn decode_value() {
+        let encoded = Format::Json.encode(&vec![1, 2, 3]).unwrap();
+        let value = Value{key: String::from("key"), value: encoded.into(), flags: Format::Json.flags(), cas: None};
+        assert_eq!(vec![1, 2, 3], Format::Json.decode_value::<Vec<u32>>(&value).unwrap());
+        let raw = Value{flags: 0, ..value};
+        assert!(Format::Json.decode_value::<Vec<u32>>(&raw).is_err());
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let cache = TypedCache::new(InMemory::new(), Format::Json);
+        let mut scores = HashMap::new();
+        scores.insert(String::from("a"), (1u32, Some(2.5f64)));
+        scores.insert(String::from("b"), (3, None));
+        cache.set_as(String::from("scores"), &scores, 0).await.unwrap();
+        assert_eq!(cache.get_as::<HashMap<String, (u32, Option<f64>)>>(String::from("scores")).await.unwrap(), Some(scores));
+        assert_eq!(cache.get_as::<Vec<u32>>(String::from("missing")).await.unwrap(), None);
+        assert!(cache.get_as::<Vec<u32>>(String::from("scores")).await.is_err());
+        cache.api().set(String::from("raw"), Bytes::from_static(b"[1]"), 0, 0).await.unwrap();
+        assert!(cache.get_as::<Vec<u32>>(String::from("raw")).await.is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use bytes::Bytes;
+use std::io;
+
+use crate::value::Value;
+use crate::api::Api;
+use crate::backoff::Backoff;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+// Optimistic read-modify-write on top of `gets`, `cas` and `add`.
+pub struct Updater<A> {
+    api: A,
+    max_attempts: u32,
+    backoff: Backoff,
+}
+
+impl<A> Updater<A> {
+    pub fn new(api: A) -> Updater<A> {
+        Updater{api: api, max_attempts: DEFAULT_MAX_ATTEMPTS, backoff: Backoff::default()}
+    }
+
+    pub fn with_max_attempts(self, max_attempts: u32) -> Updater<A> {
+        Updater{max_attempts: max_attempts, ..self}
+    }
+
+    pub fn with_backoff(self, backoff: Backoff) -> Updater<A> {
+        Updater{backoff: backoff, ..self}
+    }
+}
+
+impl<A: Api<io::Error>> Updater<A> {
+    // Applies `f` to the current value of `key` (or `None` if it is missing)
+    // and stores the result with `cas`, or `add` if the key was missing.  If
+    // another writer got there first, the value is re-read and `f` applied
+    // again, up to the configured number of attempts.  Returns the value
+    // stored, or `None` if `f` declined to store anything.
+    pub async fn update<F>(&self, key: String, expiry: u32, mut f: F) -> io::Result<Option<Bytes>>
+        where F: FnMut(Option<Value>) -> Option<Vec<u8>> {
+        let mut attempt = 0;
+        loop {
+            let current = self.api.gets(vec![key.clone()]).await?.into_iter().next();
+            let updated = match f(current.clone()) {
+                Some(updated) => Bytes::from(updated),
+                None => return Ok(None),
+            };
+            let result = match current {
+                Some(current) => self.api.cas(key.clone(), updated.clone(), current.flags, expiry, current.cas.unwrap_or(0)).await,
+                None => self.api.add(key.clone(), updated.clone(), 0, expiry).await,
+            };
+            match result {
+                Ok(()) => return Ok(Some(updated)),
+                Err(ref err) if is_conflict(err) && attempt + 1 < self.max_attempts => {
+                    tokio::time::sleep(self.backoff.delay(attempt)).await;
+                    attempt += 1;
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+// `cas` reports `EXISTS` if someone else modified the value and `NOT_FOUND`
+// if it was deleted; `add` fails if someone else created it.
+fn is_conflict(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::AlreadyExists | io::ErrorKind::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::io;
+    use std::time::Duration;
+    use crate::api::Api;
+    use crate::backoff::Backoff;
+    use crate::memory::InMemory;
+    use crate::mock::{Expectation, MockService};
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::update::Updater;
+    use crate::value::Value;
+
+    fn backoff() -> Backoff {
+        Backoff::new(Duration::from_millis(1), Duration::from_millis(1))
+    }
+
+    fn current(value: &'static [u8], cas: u64) -> Response {
+        Response::Values(vec![Value{key: String::from("a"), value: Bytes::from_static(value), flags: 0, cas: Some(cas)}])
+    }
+
+    fn append(current: Option<Value>) -> Option<Vec<u8>> {
+        let mut value = current.map(|value| value.value.to_vec()).unwrap_or_default();
+        value.push(b'x');
+        Some(value)
+    }
+
+    #[tokio::test]
+    async fn retry() {
+        let mock = MockService::new();
+        mock.expect(Expectation::any().respond(current(b"1", 1)))
+            .expect(Expectation::any().respond(Response::Exists))
+            .expect(Expectation::any().respond(current(b"2", 2)))
+            .expect(Expectation::any().respond(Response::Stored));
+        let updater = Updater::new(mock.clone()).with_backoff(backoff());
+        assert_eq!(updater.update(String::from("a"), 0, append).await.unwrap().unwrap(), Bytes::from_static(b"2x"));
+        mock.verify();
+        assert!(matches!(mock.calls()[3], Request::Cas{cas: 2, ..}));
+
+        let cache = InMemory::new();
+        let updater = Updater::new(cache.clone());
+        assert_eq!(updater.update(String::from("a"), 0, append).await.unwrap().unwrap(), Bytes::from_static(b"x"));
+        assert_eq!(updater.update(String::from("a"), 0, append).await.unwrap().unwrap(), Bytes::from_static(b"xx"));
+        assert_eq!(updater.update(String::from("a"), 0, |_| None).await.unwrap(), None);
+        assert_eq!(&cache.get(vec![String::from("a")]).await.unwrap()[0].value[..], b"xx");
+    }
+
+    #[tokio::test]
+    async fn give_up() {
+        let mock = MockService::new();
+        for cas in 0..3 {
+            mock.expect(Expectation::any().respond(current(b"1", cas)))
+                .expect(Expectation::any().respond(Response::Exists));
+        }
+        let updater = Updater::new(mock.clone()).with_max_attempts(3).with_backoff(backoff());
+        let err = updater.update(String::from("a"), 0, append).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        mock.verify();
+        assert_eq!(mock.calls().len(), 6);
+    }
+}
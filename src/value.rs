@@ -1,19 +1,22 @@
+use bytes::{BufMut, Bytes};
 use std::str;
 use std::str::FromStr;
-use nom::digit;
+use nom::{digit, IResult};
 
-use ::parse_utils::is_key_char;
+use crate::parse_utils::{is_key_char, payload};
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Value {
     pub key: String,
-    pub value: Vec<u8>,
+    pub value: Bytes,
     pub flags: u16,
     pub cas: Option<u64>,
 }
 
 impl Value {
-    named!(pub parse<&[u8], Value>,
+    // Parses a `VALUE` line, returning the value and the length of the payload
+    // that follows.  The payload is left empty.
+    named!(pub parse_header<&[u8], (Value, u32)>,
         chain!(
             tag!("VALUE ") ~
             key: map_res!(take_while!(is_key_char), |x: &[u8]| String::from_utf8(x.to_vec())) ~
@@ -25,39 +28,51 @@ impl Value {
                 tag!(" ") ~
                 cas: map_res!(map_res!(digit, str::from_utf8), u64::from_str),
                 || cas)) ~
-            tag!("\r\n") ~
-            value: map!(take!(len), |x: &[u8]| x.to_vec()) ~
             tag!("\r\n"),
-            || Value{key: key, value: value, flags: flags, cas: cas}));
-
-    pub fn build(&self, buf: &mut Vec<u8>) {
-        buf.extend_from_slice(b"VALUE ");
-        buf.extend_from_slice(self.key.as_bytes());
-        buf.extend_from_slice(b" ");
-        buf.extend_from_slice(self.flags.to_string().as_bytes());
-        buf.extend_from_slice(b" ");
-        buf.extend_from_slice(self.value.len().to_string().as_bytes());
-        match self.cas {
+            || (Value{key: key, value: Bytes::new(), flags: flags, cas: cas}, len)));
+
+    pub fn parse(input: &[u8]) -> IResult<&[u8], Value> {
+        match Value::parse_header(input) {
+            IResult::Done(input, (value, len)) => payload(input, len).map(|data| Value{value: data, ..value}),
+            IResult::Error(err) => IResult::Error(err),
+            IResult::Incomplete(needed) => IResult::Incomplete(needed),
+        }
+    }
+
+    pub fn build<B: BufMut>(&self, buf: &mut B) {
+        Value::build_header(&self.key, self.flags, self.value.len(), self.cas, buf);
+        buf.put_slice(&self.value[..]);
+        buf.put_slice(b"\r\n");
+    }
+
+    // Writes the `VALUE` line for a payload of `len` bytes.
+    pub(crate) fn build_header<B: BufMut>(key: &str, flags: u16, len: usize, cas: Option<u64>, buf: &mut B) {
+        buf.put_slice(b"VALUE ");
+        buf.put_slice(key.as_bytes());
+        buf.put_slice(b" ");
+        buf.put_slice(flags.to_string().as_bytes());
+        buf.put_slice(b" ");
+        buf.put_slice(len.to_string().as_bytes());
+        match cas {
             Some(cas) => {
-                buf.extend_from_slice(b" ");
-                buf.extend_from_slice(cas.to_string().as_bytes());
+                buf.put_slice(b" ");
+                buf.put_slice(cas.to_string().as_bytes());
             },
             None => {}
         }
-        buf.extend_from_slice(b"\r\n");
-        buf.extend_from_slice(self.value.as_slice());
-        buf.extend_from_slice(b"\r\n");
+        buf.put_slice(b"\r\n");
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use ::value::Value;
+    use bytes::Bytes;
+    use crate::value::Value;
 
     #[test]
     fn build() {
         let mut buf = Vec::new();
-        Value{key: String::from("key"), value: b"value".to_vec(), flags: 1, cas: Some(1)}.build(&mut buf);
+        Value{key: String::from("key"), value: Bytes::from_static(b"value"), flags: 1, cas: Some(1)}.build(&mut buf);
         assert_eq!(b"VALUE key 1 5 1\r\nvalue\r\n", buf.as_slice());
     }
 }